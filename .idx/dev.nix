@@ -40,7 +40,7 @@
         web = {
           # Example: run "npm run dev" with PORT set to IDX's defined port for previews,
           # and show it in IDX's web preview panel
-          command = ["target/debug/asg" "tests/blog_light_theme/src" "tests/blog_light_theme/output"];
+          command = ["target/debug/asg" "serve" "tests/blog_light_theme/src" "tests/blog_light_theme/output" "--port" "$PORT"];
           manager = "web";
           env = {
             # Environment variables to set for your server
@@ -5,7 +5,8 @@ function circle(radius)
 </svg>
 	]]
 
-	return svg
+	-- SVG built here is trusted, so {{ circle(10) }} shows it as is.
+	return raw(svg)
 end
 
 function split (inputstr, sep)
@@ -62,6 +63,6 @@ function plot(f, start_val, end_val, step)
 	table.insert(svg,[[" fill="transparent" stroke="black"/>]])
 
 	table.insert(svg,"</svg>")
-	return table.concat(svg)
+	return raw(table.concat(svg))
 
 end
\ No newline at end of file
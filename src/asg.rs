@@ -1,26 +1,92 @@
 /// File process
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{fs, time};
 
+pub mod blocks;
+pub mod build_cache;
+pub mod build_options;
+pub mod build_report;
 pub mod buildinfo;
+pub mod collections;
 pub mod csv;
+pub mod data_pages;
 pub mod date_format;
+pub mod dependencies;
 pub mod displayluaerror;
+pub mod emitted_pages;
+pub mod escaping;
+pub mod front_matter;
 pub mod git_times;
 pub mod handle_html;
 pub mod highlight_syntax;
+pub mod hooks;
+pub mod ignore_rules;
 pub mod lua_environment;
+pub mod pagination;
+pub mod parallel_build;
+pub mod partials;
+pub mod scaffold;
+pub mod taxonomies;
+pub mod template;
 pub mod tokenizer;
 
-use lua_environment::LuaEnvironment;
+use build_report::BuildReport;
+use lua_environment::{FileInfo, LuaEnvironment, SuspendedPage};
 use notify_debouncer_full::notify;
 
+/// A page whose metadata is known, waiting for the metadata of the other pages to be rendered.
+pub struct PreparedPage {
+    input_file: PathBuf,
+    output_directory: PathBuf,
+    /// Path of the output file relative to `output_directory`.
+    destination_url: String,
+    /// Time spent compiling the body of the page.
+    preparation_time: time::Duration,
+    /// None when the body needs to be compiled again, see `prepare_file`.
+    body: Option<(tokenizer::PageBody, SuspendedPage)>,
+    kind: PageKind,
+}
+
+/// What a file of the input directory writes.
+enum PageKind {
+    /// A template, written with the pages it emits.
+    Template,
+    /// A `.lua` file, which only writes the pages it emits.
+    Generator,
+    /// A data file declared with `data_pages`, which writes a page per row.
+    DataPages(data_pages::DataPages),
+    /// The pages of a taxonomy declared with `taxonomy_pages`, written by config.lua.
+    Taxonomy(taxonomies::TaxonomyPages),
+}
+
+/// Whether `input_file` is a `.lua` file generating pages with `emit_page`.
+/// config.lua is not one, it runs before every page.
+fn is_generator(input_file: &Path, base_input_directory: &Path) -> bool {
+    input_file.extension().is_some_and(|e| e == "lua")
+        && input_file != base_input_directory.join("config.lua")
+}
+
+/// Generate `input_file` from start to finish. Its metadata is updated before it is rendered.
 pub fn generate_file(
     env: &mut LuaEnvironment,
     input_file: &Path,
     base_input_directory: &Path,
     output_directory: &Path,
 ) {
+    if let Some(page) = prepare_file(env, input_file, base_input_directory, output_directory) {
+        render_page(env, page, base_input_directory);
+    }
+}
+
+/// First step of the generation of `input_file`: files which are not templates are copied,
+/// and the body of templates is compiled to know their metadata (title, tags, ...).
+/// Returns the page to give to `render_page` once the metadata of every page is known.
+pub fn prepare_file(
+    env: &mut LuaEnvironment,
+    input_file: &Path,
+    base_input_directory: &Path,
+    output_directory: &Path,
+) -> Option<PreparedPage> {
     let mut should_be_compiled = false;
     for format in tokenizer::LUA_TEMPLATE_FORMATS {
         if input_file.to_string_lossy().ends_with(format) {
@@ -29,42 +95,275 @@ pub fn generate_file(
         }
     }
 
+    let is_incremental_build = env.build_cache.directory().is_some()
+        && env.get_config("incrementalBuild") != Some("false".to_string());
+
+    // Generators have no metadata and are run every time, as they have no output of their own.
+    if is_generator(input_file, base_input_directory) {
+        return Some(PreparedPage {
+            input_file: input_file.to_path_buf(),
+            output_directory: output_directory.to_path_buf(),
+            destination_url: tokenizer::get_destination_url(input_file, base_input_directory),
+            preparation_time: time::Duration::ZERO,
+            body: None,
+            kind: PageKind::Generator,
+        });
+    }
+
+    let emitting_kind =
+        if let Some(data_pages) = env.data_pages_of(input_file, base_input_directory) {
+            Some(PageKind::DataPages(data_pages))
+        } else {
+            env.taxonomy_pages_of(input_file, base_input_directory)
+                .map(PageKind::Taxonomy)
+        };
+    if let Some(kind) = emitting_kind {
+        let emitted_files: Vec<PathBuf> = env
+            .cache
+            .borrow()
+            .emitted_pages
+            .get(input_file)
+            .into_iter()
+            .flatten()
+            .map(|url| output_directory.join(url))
+            .collect();
+        if is_incremental_build
+            && emitted_files.iter().all(|file| file.exists())
+            && emitted_files.first().is_some_and(|first_file| {
+                env.build_cache
+                    .is_up_to_date(input_file, first_file, &env.dependencies.borrow())
+            })
+        {
+            env.report
+                .borrow_mut()
+                .files_unchanged
+                .extend(emitted_files);
+            return None;
+        }
+        return Some(PreparedPage {
+            input_file: input_file.to_path_buf(),
+            output_directory: output_directory.to_path_buf(),
+            destination_url: String::new(),
+            preparation_time: time::Duration::ZERO,
+            body: None,
+            kind,
+        });
+    }
+
     if !should_be_compiled {
         let destination_url = tokenizer::get_destination_url(input_file, base_input_directory);
         let output_file = output_directory.join(&destination_url);
-        let _ = fs::copy(input_file, &output_file);
-        return;
-    }
-
-    // Let's run config.lua if it exists
-    let config_file = base_input_directory.join("config.lua");
-    #[allow(clippy::print_stdout)]
-    if config_file.exists() {
-        env.run_file_and_display_error(&config_file);
-    } else {
-        println!(
-            "Error: Config file not at {}",
-            config_file.to_string_lossy()
-        );
+        if is_incremental_build && is_copy_up_to_date(input_file, &output_file) {
+            env.report.borrow_mut().files_unchanged.push(output_file);
+            return None;
+        }
+        if let Some(prefix) = output_file.parent() {
+            let _ = fs::create_dir_all(prefix);
+        }
+        match fs::copy(input_file, &output_file) {
+            Ok(_) => {
+                hooks::file_copied(env, input_file, &output_file);
+                env.report.borrow_mut().files_copied.push(output_file);
+            }
+            Err(e) => env.report.borrow_mut().add_error(
+                &input_file.to_string_lossy(),
+                &format!("Could not copy file: {e}"),
+            ),
+        }
+        return None;
     }
 
     let is_debug_info = env.is_enabled("debugInfo");
-    let is_profiling_enabled = env.is_enabled("profiler");
     let generation_instant_start = time::Instant::now();
 
-    let destination_url = tokenizer::get_destination_url(input_file, base_input_directory);
+    let destination_url = env.destination_url(input_file, base_input_directory);
     let output_file = output_directory.join(&destination_url);
 
+    if is_incremental_build
+        && env
+            .build_cache
+            .is_up_to_date(input_file, &output_file, &env.dependencies.borrow())
+    {
+        env.report.borrow_mut().files_unchanged.push(output_file);
+        return None;
+    }
+
     #[allow(clippy::print_stdout)]
     if is_debug_info {
         println!("Compiling {}", input_file.to_string_lossy());
     }
 
-    let maybe_str =
-        tokenizer::compile_file(env, input_file, Some(&output_file), base_input_directory);
+    let message_count = env.report.borrow().message_count();
+    env.begin_page(input_file, base_input_directory);
+    env.is_listed_early.set(false);
+    env.is_quiet = true;
+    let page_body = tokenizer::compile_page_body(env, input_file, base_input_directory);
+    env.is_quiet = false;
+    // A page listing pages where it could not wait for the metadata of every page, like inside
+    // a `table.sort` comparison function, can miss pages: it is compiled again once every page
+    // was prepared, and its problems are reported then. So are pages which could not be read.
+    let body = match page_body {
+        Some(page_body) if !env.is_listed_early.get() => {
+            env.print_held_errors();
+            Some((page_body, env.suspend_page()))
+        }
+        _ => {
+            env.discard_held_errors(message_count);
+            None
+        }
+    };
 
-    #[allow(clippy::print_stdout)]
-    if let Some(content) = maybe_str {
+    Some(PreparedPage {
+        input_file: input_file.to_path_buf(),
+        output_directory: output_directory.to_path_buf(),
+        destination_url,
+        preparation_time: generation_instant_start.elapsed(),
+        body,
+        kind: PageKind::Template,
+    })
+}
+
+/// Second step of the generation of a page: apply its layouts and write it.
+/// Paginated pages are compiled again for every page of the list after the first one.
+#[allow(clippy::print_stdout)]
+pub fn render_page(env: &mut LuaEnvironment, page: PreparedPage, base_input_directory: &Path) {
+    let is_debug_info = env.is_enabled("debugInfo");
+    let is_profiling_enabled = env.is_enabled("profiler");
+    let generation_instant_start = time::Instant::now();
+    let PreparedPage {
+        input_file,
+        output_directory,
+        destination_url,
+        preparation_time,
+        body,
+        kind,
+    } = page;
+    let input_file = input_file.as_path();
+    let output_file = output_directory.join(&destination_url);
+
+    let mut outputs = vec![];
+    let mut is_compiled = true;
+    let emitted = match &kind {
+        PageKind::Generator => {
+            env.begin_page(input_file, base_input_directory);
+            env.run_generator(input_file);
+            Ok(())
+        }
+        PageKind::DataPages(data_pages) => {
+            env.begin_page(input_file, base_input_directory);
+            data_pages::emit(env, data_pages, base_input_directory)
+        }
+        PageKind::Taxonomy(taxonomy_pages) => {
+            env.begin_page(input_file, base_input_directory);
+            taxonomies::emit(env, taxonomy_pages, base_input_directory)
+        }
+        PageKind::Template => {
+            let maybe_str = if let Some((page_body, suspended_page)) = body {
+                env.resume_page(suspended_page);
+                tokenizer::apply_layouts(env, page_body, base_input_directory)
+            } else {
+                env.begin_page(input_file, base_input_directory);
+                tokenizer::compile_file(env, input_file, Some(&output_file), base_input_directory)
+            };
+            let Some(content) = maybe_str else {
+                env.end_page(input_file);
+                println!("Error: Could not compile file {}", input_file.display());
+                env.build_cache.remove(input_file);
+                env.report
+                    .borrow_mut()
+                    .add_error(&input_file.to_string_lossy(), "Could not compile file");
+                return;
+            };
+            outputs.push((
+                output_file.clone(),
+                content,
+                preparation_time + generation_instant_start.elapsed(),
+            ));
+
+            let page_count = env.pagination.borrow().page_count.unwrap_or(1);
+            for number in 2..=page_count {
+                let instant_start = time::Instant::now();
+                env.restart_page(input_file, base_input_directory);
+                env.pagination.borrow_mut().current = number;
+                let paginated_output_file =
+                    output_directory.join(pagination::page_url(&destination_url, number));
+                match tokenizer::compile_file(
+                    env,
+                    input_file,
+                    Some(&paginated_output_file),
+                    base_input_directory,
+                ) {
+                    Some(content) => {
+                        outputs.push((paginated_output_file, content, instant_start.elapsed()));
+                    }
+                    None => {
+                        is_compiled = false;
+                        env.report.borrow_mut().add_error(
+                            &input_file.to_string_lossy(),
+                            &format!("Could not compile page {number}"),
+                        );
+                    }
+                }
+            }
+            // The list can be shorter than during the previous build.
+            for number in page_count + 1.. {
+                let stale_file =
+                    output_directory.join(pagination::page_url(&destination_url, number));
+                if remove_output_file(&stale_file, &output_directory).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    };
+    if let Err(e) = emitted {
+        env.end_page(input_file);
+        println!(
+            "Error: Could not generate the pages of {}: {e}",
+            input_file.display()
+        );
+        env.build_cache.remove(input_file);
+        env.report
+            .borrow_mut()
+            .add_error(&input_file.to_string_lossy(), &e);
+        return;
+    }
+
+    let (emitted_outputs, is_emitted) =
+        emitted_pages::compile(env, input_file, base_input_directory, &output_directory);
+    is_compiled &= is_emitted;
+    outputs.extend(
+        emitted_outputs
+            .into_iter()
+            .map(|output| (output.output_file, output.content, output.duration)),
+    );
+    env.end_page(input_file);
+
+    let unpublished_label = env
+        .cache
+        .borrow()
+        .file_cache
+        .get(input_file)
+        .filter(|file_info| !file_info.is_published())
+        .map(FileInfo::unpublished_label);
+
+    for (output_file, mut content, delta) in outputs {
+        if let Some(label) = &unpublished_label {
+            // Drafts and scheduled pages only leave the computer of their authors once published.
+            if env.options.production {
+                if is_debug_info {
+                    println!("Skipping {}: {label}", input_file.display());
+                }
+                let _ = fs::remove_file(&output_file);
+                is_compiled = false;
+                continue;
+            }
+            if output_file.extension().is_some_and(|e| e == "html") {
+                content = handle_html::add_banner(&content, label);
+            }
+        }
+        let content =
+            hooks::page_rendered(env, input_file, &output_file, &output_directory, content);
         let prefix = output_file.parent();
         if let Some(prefix) = prefix {
             let _ = fs::create_dir_all(prefix);
@@ -73,80 +372,198 @@ pub fn generate_file(
             println!("Writing to {}", output_file.to_string_lossy());
         }
         let write_result = fs::write(&output_file, content);
-        if is_debug_info && let Err(e) = write_result {
-            println!("Error: Could not write it because {e}");
+        match write_result {
+            Ok(()) => {
+                env.report.borrow_mut().add_page(&output_file, delta);
+            }
+            Err(e) => {
+                println!("Error: Could not write it because {e}");
+                is_compiled = false;
+                env.report.borrow_mut().add_error(
+                    &output_file.to_string_lossy(),
+                    &format!("Could not write file: {e}"),
+                );
+            }
         }
-        let delta = generation_instant_start.elapsed();
 
         if is_profiling_enabled {
             println!(
                 "  - {} ms to generate {}",
                 delta.as_millis(),
-                &destination_url
+                output_file
+                    .strip_prefix(&output_directory)
+                    .unwrap_or(&output_file)
+                    .display()
             );
         }
-    } else {
-        println!("Error: Could not compile file {}", input_file.display());
+    }
+
+    // Data and taxonomy pages are up to date while their first page is.
+    let cached_output_file = match kind {
+        PageKind::Template => Some(output_file),
+        PageKind::Generator => None,
+        PageKind::DataPages(_) | PageKind::Taxonomy(_) => env
+            .cache
+            .borrow()
+            .emitted_pages
+            .get(input_file)
+            .and_then(|urls| urls.first())
+            .map(|url| output_directory.join(url)),
+    };
+    match cached_output_file {
+        Some(cached_output_file) if is_compiled => {
+            env.build_cache
+                .update(input_file, &cached_output_file, &env.dependencies.borrow());
+        }
+        _ => env.build_cache.remove(input_file),
     }
 }
 
+/// A copied file does not need to be copied again when the copy is newer and has the same size.
+fn is_copy_up_to_date(input_file: &Path, output_file: &Path) -> bool {
+    let (Ok(input_metadata), Ok(output_metadata)) = (input_file.metadata(), output_file.metadata())
+    else {
+        return false;
+    };
+    let (Ok(input_modified), Ok(output_modified)) =
+        (input_metadata.modified(), output_metadata.modified())
+    else {
+        return false;
+    };
+    input_metadata.len() == output_metadata.len() && input_modified <= output_modified
+}
+
+/// Remove a page that is not generated anymore, and the directories it leaves empty
+/// inside `output_directory`, like `tags/zig/` once `tags/zig/index.html` is removed.
+pub fn remove_output_file(output_file: &Path, output_directory: &Path) -> std::io::Result<()> {
+    fs::remove_file(output_file)?;
+    let mut directory = output_file.parent();
+    while let Some(path) = directory
+        && path.starts_with(output_directory)
+        && path != output_directory
+        && fs::remove_dir(path).is_ok()
+    {
+        directory = path.parent();
+    }
+    Ok(())
+}
+
+/// Collect the files to generate inside `current_dir`, sorted so that builds are reproducible.
 fn recursive_file_walk(
-    env: &mut LuaEnvironment,
+    env: &LuaEnvironment,
     current_dir: &Path,
     input_directory: &Path,
-    output_directory: &Path,
+    files: &mut Vec<PathBuf>,
 ) {
     let iter = fs::read_dir(current_dir);
     let Ok(iter) = iter else {
         return;
     };
+    let mut paths: Vec<PathBuf> = iter.flatten().map(|entry| entry.path()).collect();
+    paths.sort();
 
-    for entry in iter {
-        let Ok(entry) = entry else {
-            continue;
-        };
-        let path = entry.path();
-
-        // Ignore data directory
-        if path.is_dir() && path.ends_with("data") {
+    for path in paths {
+        // Ignore the data and partials directories, the cache directory and the files matching the ignore rules
+        if path == input_directory.join("data")
+            || path == input_directory.join(partials::PARTIALS_DIRECTORY)
+            || env.build_cache.directory() == Some(path.as_path())
+            || env.ignore_rules.is_ignored(&path)
+        {
             continue;
         }
 
         if path.is_dir() {
-            recursive_file_walk(env, &path, input_directory, output_directory);
+            recursive_file_walk(env, &path, input_directory, files);
         } else {
-            generate_file(env, &path, input_directory, output_directory);
+            files.push(path);
         }
     }
 }
 
-pub fn process_files(env: &mut LuaEnvironment, input_directory: &Path, output_directory: &Path) {
-    // Parse posts first
+/// Build the whole website and return what happened during the build.
+pub fn process_files(
+    env: &mut LuaEnvironment,
+    input_directory: &Path,
+    output_directory: &Path,
+) -> BuildReport {
     let now = time::Instant::now();
 
-    let posts_directory = input_directory.join("posts");
-    if posts_directory.exists() {
-        recursive_file_walk(env, &posts_directory, input_directory, output_directory);
+    // config.lua runs once, every page starts from the state it leaves.
+    env.load_config(input_directory);
+    env.begin_build(input_directory);
+    hooks::build_start(env, input_directory);
+
+    let mut files = vec![];
+    recursive_file_walk(env, input_directory, input_directory, &mut files);
+    // Data files are not part of the website, the pages declared with `data_pages` are.
+    files.extend(
+        env.data_pages
+            .borrow()
+            .iter()
+            .map(|data_pages| data_pages.owner(input_directory)),
+    );
+    files.extend(
+        env.taxonomy_pages
+            .borrow()
+            .iter()
+            .map(|taxonomy_pages| taxonomy_pages.owner(input_directory)),
+    );
+
+    // Pages emitted by files deleted since the previous build, or by declarations removed from config.lua.
+    let former_sources: Vec<PathBuf> = env
+        .cache
+        .borrow()
+        .emitted_pages
+        .keys()
+        .filter(|source| !files.contains(source) && **source != hooks::owner(input_directory))
+        .cloned()
+        .collect();
+    for source in former_sources {
+        emitted_pages::remove_stale(env, &source, output_directory, vec![]);
+        env.dependencies.borrow_mut().remove_page(&source);
+        env.build_cache.remove(&source);
+    }
+
+    let jobs = env.options.jobs.min(files.len());
+    if jobs > 1 {
+        parallel_build::generate_files(env, &files, input_directory, output_directory, jobs);
+    } else {
+        // Know the metadata of every page before rendering any of them,
+        // so that the pages listing posts see all of them whatever the order of the files.
+        let pages: Vec<PreparedPage> = files
+            .iter()
+            .filter_map(|file| prepare_file(env, file, input_directory, output_directory))
+            .collect();
+        for page in pages {
+            render_page(env, page, input_directory);
+        }
     }
 
-    // Parse the rest
-    recursive_file_walk(env, input_directory, input_directory, output_directory);
+    hooks::build_end(env, input_directory, output_directory, now.elapsed());
 
+    let delta = now.elapsed();
     let is_profiling_enabled = env.is_enabled("profiler");
     #[allow(clippy::print_stdout)]
     if is_profiling_enabled {
-        let delta = now.elapsed();
         println!("Total time: {} ms", delta.as_millis());
     }
+
+    env.save_cache();
+    let mut report = env.take_report();
+    report.total_time = delta;
+    report
 }
 
+/// Update the output after `file` changed and return what happened during the update.
+/// The file itself and every page that depends on it are compiled again.
 pub fn process_file(
     env: &mut LuaEnvironment,
     event_kind: notify::EventKind,
     file: &Path,
     input_directory: &Path,
     output_directory: &Path,
-) {
+) -> BuildReport {
+    let now = time::Instant::now();
     let is_debug_info = env.is_enabled("debugInfo");
 
     #[allow(clippy::print_stdout)]
@@ -154,14 +571,62 @@ pub fn process_file(
         println!("OS Event received: {event_kind:?}");
     }
 
+    if matches!(
+        event_kind,
+        notify::EventKind::Any | notify::EventKind::Access(_) | notify::EventKind::Other
+    ) {
+        return env.take_report();
+    }
+    if let Some(cache_directory) = env.build_cache.directory()
+        && file.starts_with(cache_directory)
+    {
+        return env.take_report();
+    }
+
+    // Every page depends on config.lua and std.lua, and .asgignore decides what the pages are.
+    let is_std_file = file == env.assets_path.join("std.lua");
+    if is_std_file {
+        env.reload_std();
+    }
+    if is_std_file
+        || file == input_directory.join("config.lua")
+        || file == input_directory.join(ignore_rules::IGNORE_FILE_NAME)
+    {
+        return process_files(env, input_directory, output_directory);
+    }
+    if env.ignore_rules.is_ignored(file) {
+        return env.take_report();
+    }
+    let is_incremental_build = env.get_config("incrementalBuild") != Some("false".to_string());
+    if !is_incremental_build {
+        return process_files(env, input_directory, output_directory);
+    }
+
+    // Files outside of the input directory (like layouts in the assets),
+    // data files and partials are not pages, but pages can depend on them.
+    let is_page = file.starts_with(input_directory)
+        && !file.starts_with(input_directory.join("data"))
+        && !file.starts_with(input_directory.join(partials::PARTIALS_DIRECTORY));
+    env.begin_build(input_directory);
+    hooks::build_start(env, input_directory);
+
+    // Pages listing a taxonomy depend on the pages with its terms, before and after the change.
+    let mut file_infos: Vec<FileInfo> = env
+        .cache
+        .borrow()
+        .file_cache
+        .get(file)
+        .cloned()
+        .into_iter()
+        .collect();
+
     match event_kind {
-        notify::EventKind::Any | notify::EventKind::Access(_) | notify::EventKind::Other => {}
         notify::EventKind::Create(_) | notify::EventKind::Modify(_) => {
-            if file.exists() {
+            if is_page && file.is_file() {
                 generate_file(env, file, input_directory, output_directory);
             }
         }
-        notify::EventKind::Remove(_) => {
+        notify::EventKind::Remove(_) if is_page => {
             let mut output_file =
                 output_directory.join(file.strip_prefix(input_directory).unwrap_or(file));
             if let Some(file_str) = output_file.to_str()
@@ -169,9 +634,52 @@ pub fn process_file(
             {
                 output_file = output_file.with_extension("html");
             }
+            // Pages of collections with a permalink are written elsewhere.
+            if let Some(file_info) = env.cache.borrow().file_cache.get(file) {
+                output_file = output_directory.join(&file_info.url);
+                // So are the other pages of a paginated list.
+                for number in 2.. {
+                    let page_file =
+                        output_directory.join(pagination::page_url(&file_info.url, number));
+                    if remove_output_file(&page_file, output_directory).is_err() {
+                        break;
+                    }
+                }
+            }
             if output_file.exists() {
                 let _ = fs::remove_file(output_file);
             }
+            emitted_pages::remove_stale(env, file, output_directory, vec![]);
+            env.dependencies.borrow_mut().remove_page(file);
+            env.cache.borrow_mut().file_cache.remove(file);
+            env.build_cache.remove(file);
         }
+        _ => {}
     }
+
+    file_infos.extend(env.cache.borrow().file_cache.get(file).cloned());
+    let has_terms = |name: &str| {
+        file_infos
+            .iter()
+            .any(|file_info| !taxonomies::terms_of(file_info, name).is_empty())
+    };
+    let dependents = env.dependencies.borrow().dependents(file, has_terms);
+    for page in dependents {
+        #[allow(clippy::print_stdout)]
+        if is_debug_info {
+            println!("Rebuilding {} because it depends on it", page.display());
+        }
+        if page.exists()
+            || env.data_pages_of(&page, input_directory).is_some()
+            || env.taxonomy_pages_of(&page, input_directory).is_some()
+        {
+            generate_file(env, &page, input_directory, output_directory);
+        }
+    }
+
+    hooks::build_end(env, input_directory, output_directory, now.elapsed());
+    env.save_cache();
+    let mut report = env.take_report();
+    report.total_time = now.elapsed();
+    report
 }
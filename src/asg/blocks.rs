@@ -0,0 +1,105 @@
+/// Named blocks: regions of layouts that pages fill, like `{% block sidebar %}...{% endblock %}`.
+/// A page with a layout fills blocks instead of writing them inside its body. A layout shows
+/// a block where it declares it, with the content of the block as the default.
+/// Pages and layouts can also add their content before or after the content of the next layouts
+/// with `{% block name append %}` or `{% block name prepend %}`.
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockMode {
+    /// The content replaces the content of the next layouts.
+    Replace,
+    /// The content goes after the content of the next layouts.
+    Append,
+    /// The content goes before the content of the next layouts.
+    Prepend,
+}
+
+/// Content given to a block by a page or a layout.
+#[derive(Clone, Debug)]
+struct Fill {
+    mode: BlockMode,
+    content: String,
+}
+
+/// Blocks filled by the page being compiled and by its layouts, in the order of the layout chain.
+#[derive(Clone, Debug, Default)]
+pub struct Blocks {
+    fills: HashMap<String, Vec<Fill>>,
+}
+
+impl Blocks {
+    pub fn fill(&mut self, name: &str, mode: BlockMode, content: String) {
+        self.fills
+            .entry(name.to_string())
+            .or_default()
+            .push(Fill { mode, content });
+    }
+
+    /// Content of the block `name` declared by a layout with `default` as its content.
+    /// The fills of the files closest to the layout apply first, so the page has the last word.
+    pub fn resolve(&self, name: &str, default: String) -> String {
+        let fills = self.fills.get(name).into_iter().flatten().rev();
+        fills.fold(default, |content, fill| match fill.mode {
+            BlockMode::Replace => fill.content.clone(),
+            BlockMode::Append => content + &fill.content,
+            BlockMode::Prepend => fill.content.clone() + &content,
+        })
+    }
+}
+
+/// Name and mode of `{% block name mode %}`. Names are made of letters, digits, `_` and `-`.
+pub fn parse_header(header: &str) -> Result<(String, Option<BlockMode>), String> {
+    let mut words = header.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(format!("Invalid block name: {header}"));
+    }
+    let mode = match words.next() {
+        None => None,
+        Some("replace") => Some(BlockMode::Replace),
+        Some("append") => Some(BlockMode::Append),
+        Some("prepend") => Some(BlockMode::Prepend),
+        Some(other) => return Err(format!("Unknown block mode: {other}")),
+    };
+    if words.next().is_some() {
+        return Err(format!("Invalid block: {header}"));
+    }
+    Ok((name.to_string(), mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_resolves_blocks() {
+        let mut blocks = Blocks::default();
+        assert_eq!(blocks.resolve("head", "base".to_string()), "base");
+        // The page fills first, then its layout.
+        blocks.fill("head", BlockMode::Append, "+page".to_string());
+        blocks.fill("head", BlockMode::Prepend, "layout+".to_string());
+        assert_eq!(
+            blocks.resolve("head", "base".to_string()),
+            "layout+base+page"
+        );
+        blocks.fill("sidebar", BlockMode::Replace, "page".to_string());
+        blocks.fill("sidebar", BlockMode::Append, "+layout".to_string());
+        assert_eq!(blocks.resolve("sidebar", "base".to_string()), "page");
+    }
+
+    #[test]
+    fn it_parses_block_headers() {
+        assert_eq!(parse_header("sidebar"), Ok(("sidebar".to_string(), None)));
+        assert_eq!(
+            parse_header("head append"),
+            Ok(("head".to_string(), Some(BlockMode::Append)))
+        );
+        assert!(parse_header("= {}").is_err());
+        assert!(parse_header("head later").is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use super::build_options::BuildOptions;
+use super::buildinfo;
+use super::dependencies::{Dependency, DependencyGraph};
+use super::tokenizer::ParsingCache;
+
+const CACHE_FILE_NAME: &str = "cache.json";
+
+/// What the previous build produced for a page.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedPage {
+    /// Hash of everything the page depended on when it was compiled.
+    fingerprint: String,
+    output_file: PathBuf,
+}
+
+/// Content of the cache file stored inside the cache directory.
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    /// Caches written by another version of asg are ignored.
+    version: String,
+    pages: HashMap<PathBuf, CachedPage>,
+    parsing_cache: ParsingCache,
+    dependencies: DependencyGraph,
+}
+
+/// Remembers the pages built by previous runs of asg, so that pages whose sources,
+/// dependencies and configuration did not change are not compiled again.
+/// Without a cache directory, nothing is remembered and every page is compiled.
+#[derive(Clone, Default)]
+pub struct BuildCache {
+    directory: Option<PathBuf>,
+    /// Hash of the version of asg, the command line options, config.lua and std.lua.
+    config_fingerprint: String,
+    options_fingerprint: String,
+    pages: HashMap<PathBuf, CachedPage>,
+    /// Hashes of the files read since `begin_build`.
+    file_hashes: HashMap<PathBuf, String>,
+}
+
+/// Version of asg written in the cache. The commit hash distinguishes development builds.
+fn cache_version() -> String {
+    format!(
+        "{} {}",
+        buildinfo::get_asg_version(),
+        buildinfo::built_info::COMMIT_HASH
+    )
+}
+
+/// Hash of the content of `path`, or None if it cannot be read.
+pub fn hash_file(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    Some(blake3::hash(&content).to_hex().to_string())
+}
+
+impl BuildCache {
+    pub fn new(options: &BuildOptions) -> BuildCache {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(cache_version().as_bytes());
+        let mut overrides = options.config_overrides.clone();
+        overrides.sort();
+        for (key, value) in overrides {
+            hasher.update(format!("\0{key}={value}").as_bytes());
+        }
+        hasher.update(if options.production { b"\0p" } else { b"\0d" });
+
+        BuildCache {
+            directory: options.cache_directory.clone(),
+            options_fingerprint: hasher.finalize().to_hex().to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Directory where the cache is stored, if the cache is enabled.
+    pub fn directory(&self) -> Option<&Path> {
+        self.directory.as_deref()
+    }
+
+    /// Read the cache written by a previous run into `parsing_cache` and `dependencies`.
+    /// A missing, broken or outdated cache is ignored.
+    pub fn load(&mut self, parsing_cache: &mut ParsingCache, dependencies: &mut DependencyGraph) {
+        let Some(directory) = &self.directory else {
+            return;
+        };
+        let Ok(content) = fs::read(directory.join(CACHE_FILE_NAME)) else {
+            return;
+        };
+        let Ok(mut cache_file) = serde_json::from_slice::<CacheFile>(&content) else {
+            return;
+        };
+        if cache_file.version != cache_version() {
+            return;
+        }
+
+        // Deleted files should not appear in posts() anymore.
+        cache_file.parsing_cache.retain_existing();
+        self.pages = cache_file.pages;
+        *parsing_cache = cache_file.parsing_cache;
+        *dependencies = cache_file.dependencies;
+    }
+
+    /// Write the cache so that the next run of asg can use it.
+    pub fn save(
+        &self,
+        parsing_cache: &ParsingCache,
+        dependencies: &DependencyGraph,
+    ) -> io::Result<()> {
+        let Some(directory) = &self.directory else {
+            return Ok(());
+        };
+        let cache_file = CacheFile {
+            version: cache_version(),
+            pages: self.pages.clone(),
+            parsing_cache: parsing_cache.clone(),
+            dependencies: dependencies.clone(),
+        };
+        fs::create_dir_all(directory)?;
+        let content = serde_json::to_vec(&cache_file).map_err(io::Error::other)?;
+        fs::write(directory.join(CACHE_FILE_NAME), content)
+    }
+
+    /// Start a build. Files are hashed again, and a change to one of `config_files`
+    /// (like config.lua or std.lua) invalidates every page.
+    /// Every page is also invalidated when one of the `publish_times` (unix times) passes,
+    /// as scheduled pages appear in the pages listing posts without being modified.
+    pub fn begin_build(&mut self, config_files: &[PathBuf], publish_times: &[i64]) {
+        self.file_hashes.clear();
+        let now = chrono::Local::now().timestamp();
+        let last_publish_time = publish_times.iter().filter(|&&time| time <= now).max();
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.options_fingerprint.as_bytes());
+        for file in config_files {
+            hasher.update(self.hash(file).as_bytes());
+        }
+        hasher.update(&last_publish_time.copied().unwrap_or_default().to_le_bytes());
+        self.config_fingerprint = hasher.finalize().to_hex().to_string();
+    }
+
+    /// Whether `page` was compiled to `output_file` by a previous build and
+    /// nothing it depends on changed since.
+    pub fn is_up_to_date(
+        &mut self,
+        page: &Path,
+        output_file: &Path,
+        dependencies: &DependencyGraph,
+    ) -> bool {
+        if self.directory.is_none() || !output_file.exists() {
+            return false;
+        }
+        let Some(cached_page) = self.pages.get(page).cloned() else {
+            return false;
+        };
+        let is_watched = dependencies
+            .dependencies_of(page)
+            .is_none_or(|dependencies| !dependencies.contains(&Dependency::Unwatched));
+        is_watched
+            && cached_page.output_file == output_file
+            && self.fingerprint(page, dependencies) == cached_page.fingerprint
+    }
+
+    /// Remember that `page` was compiled to `output_file` with its current dependencies.
+    pub fn update(&mut self, page: &Path, output_file: &Path, dependencies: &DependencyGraph) {
+        if self.directory.is_none() {
+            return;
+        }
+        let fingerprint = self.fingerprint(page, dependencies);
+        self.pages.insert(
+            page.to_path_buf(),
+            CachedPage {
+                fingerprint,
+                output_file: output_file.to_path_buf(),
+            },
+        );
+    }
+
+    /// Take what `other` remembers about `pages`, which it compiled.
+    pub fn merge_pages(&mut self, other: &BuildCache, pages: &[PathBuf]) {
+        for page in pages {
+            match other.pages.get(page) {
+                Some(cached_page) => self.pages.insert(page.clone(), cached_page.clone()),
+                None => self.pages.remove(page),
+            };
+        }
+        for (path, hash) in &other.file_hashes {
+            self.file_hashes.insert(path.clone(), hash.clone());
+        }
+    }
+
+    /// Forget `page`, so that it is compiled by the next build.
+    pub fn remove(&mut self, page: &Path) {
+        self.pages.remove(page);
+    }
+
+    fn fingerprint(&mut self, page: &Path, dependencies: &DependencyGraph) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.config_fingerprint.as_bytes());
+        hasher.update(self.hash(page).as_bytes());
+        for dependency in dependencies.dependencies_of(page).into_iter().flatten() {
+            let hash = match dependency {
+                Dependency::File(path) => self.hash(path),
+                // Pages are checked before the terms of every page are known:
+                // any file of the directory can add terms.
+                Dependency::Directory(path) | Dependency::Taxonomy(path, _) => {
+                    self.hash_directory(path)
+                }
+                Dependency::Unwatched => String::new(),
+            };
+            hasher.update(hash.as_bytes());
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+
+    /// Memoized hash of a file. Missing files have a hash too, so that creating them is a change.
+    fn hash(&mut self, path: &Path) -> String {
+        if let Some(hash) = self.file_hashes.get(path) {
+            return hash.clone();
+        }
+        let hash = hash_file(path).unwrap_or_else(|| "missing".to_string());
+        self.file_hashes.insert(path.to_path_buf(), hash.clone());
+        hash
+    }
+
+    /// Hash of the names and contents of every file inside `directory`.
+    /// The cache directory is skipped: it is inside the input directory by default, and changes on every build.
+    fn hash_directory(&mut self, directory: &Path) -> String {
+        let mut hasher = blake3::Hasher::new();
+        let files: Vec<PathBuf> = WalkDir::new(directory)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| self.directory.as_deref() != Some(entry.path()))
+            .flatten()
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .collect();
+        for file in files {
+            hasher.update(file.to_string_lossy().as_bytes());
+            hasher.update(self.hash(&file).as_bytes());
+        }
+        hasher.finalize().to_hex().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_detects_changed_dependencies() {
+        let directory = std::env::temp_dir().join(format!("asg-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let page = directory.join("index.md");
+        let layout = directory.join("layout.html");
+        let output = directory.join("index.html");
+        fs::write(&page, "Hello").unwrap();
+        fs::write(&layout, "{{ body }}").unwrap();
+        fs::write(&output, "<p>Hello</p>").unwrap();
+
+        let mut dependencies = DependencyGraph::new();
+        dependencies.begin_page();
+        dependencies.record(Dependency::File(layout.clone()));
+        dependencies.end_page(&page);
+
+        let options = BuildOptions {
+            cache_directory: Some(directory.join("cache")),
+            ..Default::default()
+        };
+        let mut cache = BuildCache::new(&options);
+        cache.begin_build(&[], &[]);
+        assert!(!cache.is_up_to_date(&page, &output, &dependencies));
+        cache.update(&page, &output, &dependencies);
+        assert!(cache.is_up_to_date(&page, &output, &dependencies));
+        cache.save(&ParsingCache::new(), &dependencies).unwrap();
+
+        let mut cache = BuildCache::new(&options);
+        let mut loaded_dependencies = DependencyGraph::new();
+        cache.load(&mut ParsingCache::new(), &mut loaded_dependencies);
+        cache.begin_build(&[], &[]);
+        assert!(cache.is_up_to_date(&page, &output, &loaded_dependencies));
+
+        fs::write(&layout, "<main>{{ body }}</main>").unwrap();
+        cache.begin_build(&[], &[]);
+        assert!(!cache.is_up_to_date(&page, &output, &loaded_dependencies));
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+
+/// Options of a build that come from the command line instead of config.lua.
+#[derive(Clone, Debug, Default)]
+pub struct BuildOptions {
+    /// Directory containing std.lua and the layouts.
+    /// Defaults to the assets folder next to the executable.
+    pub asset_directory: Option<PathBuf>,
+    /// Values passed with `-D key=value`. They take precedence over `setvar`.
+    pub config_overrides: Vec<(String, String)>,
+    /// Defines the `production` global, used by base.html to remove the live reload script.
+    pub production: bool,
+    /// Directory where the cache is kept between runs. No cache is kept when it is None.
+    pub cache_directory: Option<PathBuf>,
+    /// Number of pages compiled at the same time, each by its own Lua state.
+    /// 0 and 1 both mean that pages are compiled one after the other.
+    pub jobs: usize,
+}
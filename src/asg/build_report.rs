@@ -0,0 +1,125 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use colored::Colorize;
+
+/// A problem found while building, attached to the file responsible for it.
+#[derive(Debug, Clone)]
+pub struct BuildMessage {
+    pub file: String,
+    pub message: String,
+}
+
+/// Everything that happened during a build: files written, problems found and timings.
+/// It is filled by `generate_file` and `compile_file_recursive` through `LuaEnvironment::report`.
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    pub errors: Vec<BuildMessage>,
+    pub warnings: Vec<BuildMessage>,
+    pub pages_written: Vec<PathBuf>,
+    pub files_copied: Vec<PathBuf>,
+    /// Outputs left untouched because their sources did not change since the last build.
+    pub files_unchanged: Vec<PathBuf>,
+    pub page_timings: Vec<(PathBuf, Duration)>,
+    pub total_time: Duration,
+}
+
+impl BuildReport {
+    pub fn new() -> BuildReport {
+        BuildReport::default()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn add_error(&mut self, file: &str, message: &str) {
+        self.errors.push(BuildMessage {
+            file: file.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    pub fn add_warning(&mut self, file: &str, message: &str) {
+        self.warnings.push(BuildMessage {
+            file: file.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Number of errors and warnings reported so far, see `discard_messages_since`.
+    pub fn message_count(&self) -> (usize, usize) {
+        (self.errors.len(), self.warnings.len())
+    }
+
+    /// Forget the errors and warnings reported after `message_count` returned `count`.
+    pub fn discard_messages_since(&mut self, count: (usize, usize)) {
+        self.errors.truncate(count.0);
+        self.warnings.truncate(count.1);
+    }
+
+    pub fn add_page(&mut self, output_file: &Path, duration: Duration) {
+        self.pages_written.push(output_file.to_path_buf());
+        self.page_timings
+            .push((output_file.to_path_buf(), duration));
+    }
+
+    /// Add everything reported in `other` to this report.
+    pub fn merge(&mut self, other: BuildReport) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+        self.pages_written.extend(other.pages_written);
+        self.files_copied.extend(other.files_copied);
+        self.files_unchanged.extend(other.files_unchanged);
+        self.page_timings.extend(other.page_timings);
+        self.total_time += other.total_time;
+    }
+
+    /// Print a short summary of the build. Details of the errors are printed when they happen.
+    #[allow(clippy::print_stdout)]
+    pub fn print_summary(&self, is_color: bool) {
+        let m_color = |s: &str, is_error: bool| {
+            if !is_color {
+                s.clear()
+            } else if is_error {
+                s.red()
+            } else {
+                s.yellow()
+            }
+        };
+
+        for warning in &self.warnings {
+            println!(
+                "{} {}: {}",
+                m_color("Warning:", false),
+                warning.file,
+                warning.message
+            );
+        }
+        for error in &self.errors {
+            println!(
+                "{} {}: {}",
+                m_color("Error:", true),
+                error.file,
+                error.message
+            );
+        }
+
+        let summary = format!(
+            "Built {} pages and copied {} files in {} ms ({} unchanged, {} errors, {} warnings)",
+            self.pages_written.len(),
+            self.files_copied.len(),
+            self.total_time.as_millis(),
+            self.files_unchanged.len(),
+            self.errors.len(),
+            self.warnings.len()
+        );
+        if self.has_errors() {
+            println!("❌ {}", m_color(&summary, true));
+        } else {
+            println!("✅ {summary}");
+        }
+    }
+}
@@ -0,0 +1,320 @@
+/// Collections: groups of pages listed together, like the posts of a blog.
+/// They are declared in config.lua with `collection(name, options)` and listed with `pages(name, query)`.
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local};
+use mlua::{Function, Lua, LuaSerdeExt, Table, Value as LuaValue};
+use serde_json::Value;
+
+use super::date_format::parse_date;
+use super::emitted_pages;
+use super::lua_environment::FileInfo;
+
+/// Name of the collection listed by `posts()`. It exists even if config.lua does not declare it.
+pub const POSTS: &str = "posts";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Collection {
+    pub name: String,
+    /// Directory of the pages, relative to the input directory.
+    pub directory: PathBuf,
+    /// Key used to sort the pages when the query does not choose one, see `sort_key`.
+    pub sort: String,
+    pub reverse: bool,
+    /// Layout of the pages which do not choose one.
+    pub layout: Option<String>,
+    /// Where the pages are written, like `projects/:slug/`, see `expand_permalink`.
+    pub permalink: Option<String>,
+}
+
+impl Collection {
+    /// The collection `name`, with the `options` given to `collection` in config.lua.
+    pub fn from_lua(name: &str, options: Option<&Table>) -> mlua::Result<Collection> {
+        let mut collection = Collection {
+            name: name.to_string(),
+            directory: PathBuf::from(name),
+            sort: "date".to_string(),
+            reverse: false,
+            layout: None,
+            permalink: None,
+        };
+        if let Some(options) = options {
+            if let Some(directory) = options.get::<Option<String>>("directory")? {
+                collection.directory = PathBuf::from(directory);
+            }
+            if let Some(sort) = options.get::<Option<String>>("sort")? {
+                collection.sort = sort;
+            }
+            collection.reverse = options.get::<Option<bool>>("reverse")?.unwrap_or(false);
+            collection.layout = options.get("layout")?;
+            collection.permalink = options.get("permalink")?;
+        }
+        Ok(collection)
+    }
+}
+
+/// Collections declared by config.lua.
+#[derive(Default)]
+pub struct Collections {
+    collections: Vec<Collection>,
+}
+
+impl Collections {
+    pub fn clear(&mut self) {
+        self.collections.clear();
+    }
+
+    /// Declare `collection`, replacing the collection with the same name.
+    pub fn add(&mut self, collection: Collection) {
+        self.collections.retain(|c| c.name != collection.name);
+        self.collections.push(collection);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Collection> {
+        let collection = self.collections.iter().find(|c| c.name == name);
+        match collection {
+            Some(collection) => Some(collection.clone()),
+            None if name == POSTS => Collection::from_lua(POSTS, None).ok(),
+            None => None,
+        }
+    }
+
+    /// The collection `page` belongs to. Inside nested collections, the innermost one.
+    pub fn containing(&self, page: &Path, input_directory: &Path) -> Option<&Collection> {
+        self.collections
+            .iter()
+            .filter(|c| page.starts_with(input_directory.join(&c.directory)))
+            .max_by_key(|c| c.directory.components().count())
+    }
+}
+
+/// How a page lists a collection: `pages(name, { sort = "title", reverse = true, tag = "rust", limit = 3, filter = f })`.
+/// The sort order defaults to the one of the collection. A query choosing its `sort` key sorts in ascending order
+/// unless it sets `reverse`.
+pub struct Query {
+    pub sort: String,
+    pub reverse: bool,
+    pub tag: Option<String>,
+    pub limit: Option<usize>,
+    /// Lua function called with every page, which returns whether the page is listed.
+    pub filter: Option<Function>,
+}
+
+impl Query {
+    pub fn from_lua(collection: &Collection, query: Option<&Table>) -> mlua::Result<Query> {
+        let mut result = Query {
+            sort: collection.sort.clone(),
+            reverse: collection.reverse,
+            tag: None,
+            limit: None,
+            filter: None,
+        };
+        if let Some(query) = query {
+            if let Some(sort) = query.get::<Option<String>>("sort")? {
+                result.sort = sort;
+                result.reverse = false;
+            }
+            if let Some(reverse) = query.get::<Option<bool>>("reverse")? {
+                result.reverse = reverse;
+            }
+            result.tag = query.get("tag")?;
+            result.limit = query.get("limit")?;
+            result.filter = query.get("filter")?;
+        }
+        Ok(result)
+    }
+}
+
+/// Published pages of `file_cache` inside `directory`, with `tag` if the query has one, sorted.
+/// Pages with the same sort key are sorted by file name, so that the order is the same on every computer.
+pub fn list(
+    directory: &Path,
+    file_cache: &HashMap<PathBuf, FileInfo>,
+    query: &Query,
+) -> Vec<FileInfo> {
+    let mut pages: Vec<FileInfo> = file_cache
+        .iter()
+        .filter(|(path, file_info)| {
+            // Drafts and scheduled pages are not listed.
+            path.starts_with(directory)
+                && file_info.is_published()
+                && query
+                    .tag
+                    .as_ref()
+                    .is_none_or(|tag| file_info.tags.iter().any(|t| t.trim() == tag))
+        })
+        .map(|(_, file_info)| file_info.clone())
+        .collect();
+    pages.sort_by(|a, b| {
+        let ordering = sort_key(a, &query.sort)
+            .partial_cmp(&sort_key(b, &query.sort))
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.filename.cmp(&b.filename));
+        if query.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+    pages
+}
+
+/// Pages as Lua tables, without the ones for which `filter` returns false, and at most `limit` of them.
+/// Their `url` starts from the site root, like `/posts/hello.html`, so it works from any listing page.
+pub fn to_lua(
+    lua: &Lua,
+    file_infos: Vec<FileInfo>,
+    filter: Option<&Function>,
+    limit: Option<usize>,
+) -> mlua::Result<Vec<LuaValue>> {
+    let mut pages = vec![];
+    for file_info in file_infos {
+        if limit.is_some_and(|limit| pages.len() >= limit) {
+            break;
+        }
+        let page = lua.to_value(&file_info)?;
+        if let LuaValue::Table(table) = &page {
+            table.set("url", emitted_pages::site_url(&file_info.url))?;
+        }
+        if let Some(filter) = filter
+            && !filter.call::<bool>(page.clone())?
+        {
+            continue;
+        }
+        pages.push(page);
+    }
+    Ok(pages)
+}
+
+/// Value compared to sort pages. Pages without the key come first.
+#[derive(PartialEq, PartialOrd)]
+enum SortKey {
+    Missing,
+    Number(f64),
+    Date(DateTime<Local>),
+    Text(String),
+}
+
+/// `key` can be `date` (the `publish_date`, or else `created_at`), a field of `FileInfo`
+/// or a front matter key. Text which looks like a date is sorted like a date.
+fn sort_key(file_info: &FileInfo, key: &str) -> SortKey {
+    let text = |s: &str| match parse_date(s) {
+        Some(date) => SortKey::Date(date),
+        None => SortKey::Text(s.to_string()),
+    };
+    match key {
+        "date" => file_info.date().map_or(SortKey::Missing, SortKey::Date),
+        "created_at" => text(&file_info.created_at),
+        "last_modified" => text(&file_info.last_modified),
+        "title" => SortKey::Text(file_info.title.clone()),
+        "description" => SortKey::Text(file_info.description.clone()),
+        "filename" => SortKey::Text(file_info.filename.clone()),
+        "url" => SortKey::Text(file_info.url.clone()),
+        "word_count" => SortKey::Number(file_info.word_count as f64),
+        _ => match file_info.extra.get(key) {
+            None | Some(Value::Null) => SortKey::Missing,
+            Some(Value::Number(n)) => SortKey::Number(n.as_f64().unwrap_or_default()),
+            Some(Value::Bool(b)) => SortKey::Number(f64::from(u8::from(*b))),
+            Some(Value::String(s)) => text(s),
+            Some(other) => SortKey::Text(other.to_string()),
+        },
+    }
+}
+
+/// Output path of a page written with the permalink `pattern`, relative to the output directory.
+/// `:slug` is replaced by `slug`, `:path` by the path of the page inside its collection
+/// without its extension, and `:year`, `:month` and `:day` by its date.
+/// Patterns ending with `/` are directories: the page is their `index.html`.
+pub fn expand_permalink(
+    pattern: &str,
+    relative_path: &Path,
+    slug: &str,
+    date: DateTime<Local>,
+) -> String {
+    let path = relative_path
+        .with_extension("")
+        .to_string_lossy()
+        .replace('\\', "/");
+    let mut result = pattern
+        .trim_start_matches('/')
+        .replace(":slug", slug)
+        .replace(":path", &path)
+        .replace(":year", &date.format("%Y").to_string())
+        .replace(":month", &date.format("%m").to_string())
+        .replace(":day", &date.format("%d").to_string());
+    if result.is_empty() || result.ends_with('/') {
+        result.push_str("index.html");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn file_info(filename: &str, title: &str, extra: Value) -> FileInfo {
+        FileInfo {
+            filename: filename.to_string(),
+            url: String::new(),
+            size: 0,
+            word_count: 0,
+            last_modified_os: String::new(),
+            last_modified: String::new(),
+            created_at: "01/01/2020 00:00:00".to_string(),
+            title: title.to_string(),
+            description: String::new(),
+            tags: vec!["rust".to_string()],
+            draft: false,
+            publish_date: String::new(),
+            extra: extra.as_object().cloned().unwrap_or_default(),
+        }
+    }
+
+    #[test]
+    fn it_sorts_and_filters_pages() {
+        let mut file_cache = HashMap::new();
+        let pages = [
+            ("/in/talks/a.md", "B", serde_json::json!({ "year": 2021 })),
+            ("/in/talks/b.md", "A", serde_json::json!({ "year": 2019 })),
+            ("/in/talks/c.md", "C", serde_json::json!({})),
+            ("/in/posts/d.md", "D", serde_json::json!({ "year": 2000 })),
+        ];
+        for (filename, title, extra) in pages {
+            file_cache.insert(PathBuf::from(filename), file_info(filename, title, extra));
+        }
+        let collection = Collection::from_lua("talks", None).unwrap();
+        let mut query = Query::from_lua(&collection, None).unwrap();
+        let titles = |query: &Query| -> Vec<String> {
+            list(Path::new("/in/talks"), &file_cache, query)
+                .into_iter()
+                .map(|file_info| file_info.title)
+                .collect()
+        };
+
+        // Same date: sorted by file name.
+        assert_eq!(titles(&query), ["B", "A", "C"]);
+        query.sort = "title".to_string();
+        query.reverse = true;
+        assert_eq!(titles(&query), ["C", "B", "A"]);
+        query.sort = "year".to_string();
+        query.reverse = false;
+        assert_eq!(titles(&query), ["C", "A", "B"]);
+        query.tag = Some("lua".to_string());
+        assert!(titles(&query).is_empty());
+    }
+
+    #[test]
+    fn it_expands_permalinks() {
+        let date = Local.with_ymd_and_hms(2025, 3, 9, 12, 0, 0).unwrap();
+        let expand = |pattern| expand_permalink(pattern, Path::new("2025/hello.md"), "hi", date);
+        assert_eq!(expand("/projects/:slug/"), "projects/hi/index.html");
+        assert_eq!(expand(":year/:month/:day/:slug.html"), "2025/03/09/hi.html");
+        assert_eq!(expand("talks/:path.html"), "talks/2025/hello.html");
+    }
+}
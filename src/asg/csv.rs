@@ -32,3 +32,63 @@ fn read_csv_file_with_sep(file: &Path, sep: char) -> Vec<Vec<String>> {
 pub fn read_csv_file(file: &Path) -> Vec<Vec<String>> {
     read_csv_file_with_sep(file, ',')
 }
+
+/// Read a `.csv` file whose first line names the columns.
+/// Unlike `read_csv_file`, empty fields are kept so that values stay in their column,
+/// and fields can be quoted to contain commas: `"Lovelace, Ada"`.
+pub fn read_csv_records(file: &Path) -> io::Result<Vec<Vec<(String, String)>>> {
+    let content = fs::read_to_string(file)?;
+    let mut lines = content.lines().filter(|line| !line.trim().is_empty());
+    let Some(header) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let columns = split_record(header);
+    Ok(lines
+        .map(|line| {
+            columns
+                .iter()
+                .cloned()
+                .zip(
+                    split_record(line)
+                        .into_iter()
+                        .chain(std::iter::repeat(String::new())),
+                )
+                .collect()
+        })
+        .collect())
+}
+
+/// Split a line of a `.csv` file into its fields. `""` is a quote inside a quoted field.
+fn split_record(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut is_quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if is_quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => is_quoted = !is_quoted,
+            ',' if !is_quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_splits_records() {
+        assert_eq!(split_record("a,,b"), ["a", "", "b"]);
+        assert_eq!(
+            split_record("ada, \"Lovelace, Ada\",\"say \"\"hi\"\"\""),
+            ["ada", "Lovelace, Ada", "say \"hi\""]
+        );
+    }
+}
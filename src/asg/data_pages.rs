@@ -0,0 +1,180 @@
+/// Data pages: one page per row of a `.csv` or `.json` file of the data directory, declared in config.lua
+/// with `data_pages(data_file, { template = "speaker.html", url = "/speakers/{slug}.html" })`.
+/// The data file writes its pages like a page writing pages with `emit_page`: they are compiled
+/// again when the data file or the template change, and removed when their row is.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use mlua::{LuaSerdeExt, Table};
+use serde_json::{Map, Value};
+
+use super::csv;
+use super::dependencies::Dependency;
+use super::emitted_pages::{self, EmittedContent, EmittedPage};
+use super::lua_environment::LuaEnvironment;
+use super::tokenizer;
+
+pub type Row = Map<String, Value>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataPages {
+    /// Path of the data file, inside the data directory.
+    pub data_file: PathBuf,
+    /// Template compiled for every row, resolved like a layout.
+    pub template: String,
+    /// URL of the page of a row, like `/speakers/{slug}.html`, see `expand_url`.
+    pub url: String,
+}
+
+impl DataPages {
+    /// The pages of `data_file`, relative to `data_directory`, with the `options` given to `data_pages` in config.lua.
+    pub fn from_lua(
+        data_directory: &Path,
+        data_file: &str,
+        options: &Table,
+    ) -> mlua::Result<DataPages> {
+        let Some(template) = options.get::<Option<String>>("template")? else {
+            return Err(mlua::Error::runtime(format!(
+                "data_pages(\"{data_file}\") needs a template"
+            )));
+        };
+        let Some(url) = options.get::<Option<String>>("url")? else {
+            return Err(mlua::Error::runtime(format!(
+                "data_pages(\"{data_file}\") needs a url"
+            )));
+        };
+        Ok(DataPages {
+            data_file: data_directory.join(data_file),
+            template,
+            url,
+        })
+    }
+
+    /// The pages of a data file are written by config.lua, which declares them.
+    /// This path identifies them in the cache and in error messages, like `config.lua#data/speakers.csv`,
+    /// so that they depend on the data file like any page.
+    pub fn owner(&self, input_directory: &Path) -> PathBuf {
+        let data_file = self
+            .data_file
+            .strip_prefix(input_directory)
+            .unwrap_or(&self.data_file);
+        input_directory.join(format!("config.lua#{}", data_file.display()))
+    }
+}
+
+/// Rows of a `.csv` file, whose first line names the columns, or of a `.json` file containing a list of objects.
+pub fn read_rows(data_file: &Path) -> Result<Vec<Row>, String> {
+    let name = data_file.display();
+    match data_file.extension().and_then(|e| e.to_str()) {
+        Some("csv") => {
+            let records = csv::read_csv_records(data_file)
+                .map_err(|e| format!("Could not read {name}: {e}"))?;
+            Ok(records
+                .into_iter()
+                .map(|record| {
+                    record
+                        .into_iter()
+                        .map(|(column, value)| (column, Value::String(value)))
+                        .collect()
+                })
+                .collect())
+        }
+        Some("json") => {
+            let content =
+                fs::read_to_string(data_file).map_err(|e| format!("Could not read {name}: {e}"))?;
+            let value: Value = serde_json::from_str(&content)
+                .map_err(|e| format!("Invalid JSON in {name}: {e}"))?;
+            let Value::Array(rows) = value else {
+                return Err(format!("{name} should contain a list of objects"));
+            };
+            rows.into_iter()
+                .map(|row| match row {
+                    Value::Object(row) => Ok(row),
+                    _ => Err(format!("{name} should contain a list of objects")),
+                })
+                .collect()
+        }
+        _ => Err(format!(
+            "Cannot generate pages from {name}: data pages need a .csv or .json file"
+        )),
+    }
+}
+
+/// URL of the page of `row`: every `{column}` of `pattern` is replaced by the value of the column.
+pub fn expand_url(pattern: &str, row: &Row) -> Result<String, String> {
+    let mut url = String::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else {
+            return Err(format!("Missing }} in the url {pattern}"));
+        };
+        let column = &rest[start + 1..start + length];
+        let value = match row.get(column) {
+            None | Some(Value::Null) => return Err(format!("The row has no {column}")),
+            Some(Value::String(s)) if s.is_empty() => {
+                return Err(format!("The {column} of the row is empty"));
+            }
+            Some(Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+        };
+        url.push_str(&rest[..start]);
+        url.push_str(&value);
+        rest = &rest[start + length + 1..];
+    }
+    url.push_str(rest);
+    Ok(url)
+}
+
+/// Emit the page of every row of `data_pages`, with the row as the `row` global of the template.
+pub fn emit(
+    env: &LuaEnvironment,
+    data_pages: &DataPages,
+    base_input_directory: &Path,
+) -> Result<(), String> {
+    // Watching the data file compiles its pages again when it changes.
+    env.dependencies
+        .borrow_mut()
+        .record(Dependency::File(data_pages.data_file.clone()));
+    let template = Path::new(&data_pages.template);
+    if tokenizer::resolve_path(template, base_input_directory, &env.assets_path).is_none() {
+        return Err(format!("Template {} not found", data_pages.template));
+    }
+
+    let rows = read_rows(&data_pages.data_file)?;
+    let mut emitted_pages = vec![];
+    for (index, row) in rows.iter().enumerate() {
+        let url = expand_url(&data_pages.url, row)
+            .and_then(|url| emitted_pages::normalize_url(&url))
+            .map_err(|e| format!("Row {}: {e}", index + 1))?;
+        let vars = env.lua.create_table().map_err(|e| e.to_string())?;
+        let row = env.lua.to_value(row).map_err(|e| e.to_string())?;
+        vars.set("row", row).map_err(|e| e.to_string())?;
+        emitted_pages.push(EmittedPage {
+            url,
+            content: EmittedContent::Template(template.to_path_buf()),
+            vars: Some(vars),
+        });
+    }
+    env.emitted_pages.borrow_mut().extend(emitted_pages);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_expands_urls() {
+        let row: Row =
+            serde_json::from_str(r#"{ "slug": "ada", "year": 2025, "bio": "" }"#).unwrap();
+        assert_eq!(
+            expand_url("/speakers/{year}/{slug}.html", &row).unwrap(),
+            "/speakers/2025/ada.html"
+        );
+        assert!(expand_url("/speakers/{name}.html", &row).is_err());
+        assert!(expand_url("/speakers/{bio}.html", &row).is_err());
+        assert!(expand_url("/speakers/{slug.html", &row).is_err());
+    }
+}
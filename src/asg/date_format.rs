@@ -1,2 +1,56 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
 /// The main format which is used to represent dates in Lua
 pub const DATE_FORMAT: &str = "%d/%m/%Y %T";
+
+/// Formats accepted for dates written by users, like the `publish_date` of a page.
+const DATE_TIME_FORMATS: &[&str] = &[
+    DATE_FORMAT,
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+];
+const DATE_ONLY_FORMATS: &[&str] = &["%Y-%m-%d", "%d/%m/%Y"];
+
+/// Parse a date in one of the formats above, or RFC 3339. Dates without a timezone are local.
+/// A date without a time means midnight.
+pub fn parse_date(s: &str) -> Option<DateTime<Local>> {
+    let s = s.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Some(date.with_timezone(&Local));
+    }
+    let naive_date = DATE_TIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            DATE_ONLY_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(s, format).ok())
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })?;
+    Local.from_local_datetime(&naive_date).earliest()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, Timelike};
+
+    use super::*;
+
+    #[test]
+    fn it_parses_dates() {
+        let date = parse_date("2025-11-02").unwrap();
+        assert_eq!((date.year(), date.month(), date.day()), (2025, 11, 2));
+        assert_eq!(date.hour(), 0);
+
+        let date = parse_date("2025-11-02 14:30").unwrap();
+        assert_eq!((date.hour(), date.minute()), (14, 30));
+
+        let date = parse_date("02/11/2025 14:30:05").unwrap();
+        assert_eq!((date.month(), date.second()), (11, 5));
+
+        assert!(parse_date("2025-11-02T14:30:00+01:00").is_some());
+        assert!(parse_date("next week").is_none());
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Something read by a page while it is compiled.
+/// When it changes, the page needs to be compiled again.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Dependency {
+    /// A file like a layout, an asset or a data file.
+    File(PathBuf),
+    /// Any file inside a directory, like the posts listed by `posts()`.
+    Directory(PathBuf),
+    /// The pages inside a directory with terms of a taxonomy, like the pages listed by `taxonomy("tags")`.
+    Taxonomy(PathBuf, String),
+    /// Something no file change shows, like the time, git or the `site` table shared by pages.
+    /// The build cache never skips the page.
+    Unwatched,
+}
+
+impl Dependency {
+    /// `has_terms` tells whether the changed file has, or had before the change, terms of a taxonomy.
+    fn is_affected_by(&self, changed_file: &Path, has_terms: &impl Fn(&str) -> bool) -> bool {
+        match self {
+            Dependency::File(path) => path == changed_file,
+            Dependency::Directory(path) => changed_file.starts_with(path),
+            Dependency::Taxonomy(path, name) => changed_file.starts_with(path) && has_terms(name),
+            Dependency::Unwatched => false,
+        }
+    }
+}
+
+/// Records what every page depends on, to rebuild exactly the pages affected by a change.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct DependencyGraph {
+    pages: HashMap<PathBuf, BTreeSet<Dependency>>,
+    /// Dependencies of the page being compiled.
+    #[serde(skip)]
+    current: BTreeSet<Dependency>,
+}
+
+impl DependencyGraph {
+    pub fn new() -> DependencyGraph {
+        DependencyGraph::default()
+    }
+
+    pub fn begin_page(&mut self) {
+        self.current.clear();
+    }
+
+    pub fn record(&mut self, dependency: Dependency) {
+        self.current.insert(dependency);
+    }
+
+    /// Store the dependencies recorded since `begin_page` as the dependencies of `page`.
+    pub fn end_page(&mut self, page: &Path) {
+        let dependencies = std::mem::take(&mut self.current);
+        self.pages.insert(page.to_path_buf(), dependencies);
+    }
+
+    /// Take the dependencies recorded since `begin_page`, to continue the page later.
+    pub fn suspend_page(&mut self) -> BTreeSet<Dependency> {
+        std::mem::take(&mut self.current)
+    }
+
+    /// Continue recording the dependencies of a page suspended with `suspend_page`.
+    pub fn resume_page(&mut self, dependencies: BTreeSet<Dependency>) {
+        self.current = dependencies;
+    }
+
+    pub fn remove_page(&mut self, page: &Path) {
+        self.pages.remove(page);
+    }
+
+    /// Take the dependencies of `pages` from `other`, which compiled them.
+    pub fn merge_pages(&mut self, other: &DependencyGraph, pages: &[PathBuf]) {
+        for page in pages {
+            match other.pages.get(page) {
+                Some(dependencies) => self.pages.insert(page.clone(), dependencies.clone()),
+                None => self.pages.remove(page),
+            };
+        }
+    }
+
+    pub fn dependencies_of(&self, page: &Path) -> Option<&BTreeSet<Dependency>> {
+        self.pages.get(page)
+    }
+
+    /// Pages to compile again when `changed_file` was modified, sorted by path.
+    /// The page `changed_file` itself is not included.
+    /// `has_terms` tells whether the changed file has, or had before the change, terms of a taxonomy.
+    pub fn dependents(
+        &self,
+        changed_file: &Path,
+        has_terms: impl Fn(&str) -> bool,
+    ) -> Vec<PathBuf> {
+        let mut result: Vec<PathBuf> = self
+            .pages
+            .iter()
+            .filter(|(page, dependencies)| {
+                page.as_path() != changed_file
+                    && dependencies
+                        .iter()
+                        .any(|d| d.is_affected_by(changed_file, &has_terms))
+            })
+            .map(|(page, _)| page.clone())
+            .collect();
+        result.sort();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_terms(_: &str) -> bool {
+        false
+    }
+
+    #[test]
+    fn it_finds_dependents() {
+        let mut graph = DependencyGraph::new();
+        graph.begin_page();
+        graph.record(Dependency::File(PathBuf::from("/in/index.md")));
+        graph.record(Dependency::File(PathBuf::from("/assets/light.html")));
+        graph.record(Dependency::Directory(PathBuf::from("/in/posts")));
+        graph.end_page(Path::new("/in/index.md"));
+
+        graph.begin_page();
+        graph.record(Dependency::File(PathBuf::from("/in/posts/a.md")));
+        graph.record(Dependency::File(PathBuf::from("/assets/light.html")));
+        graph.end_page(Path::new("/in/posts/a.md"));
+
+        assert_eq!(
+            graph.dependents(Path::new("/assets/light.html"), no_terms),
+            vec![
+                PathBuf::from("/in/index.md"),
+                PathBuf::from("/in/posts/a.md")
+            ]
+        );
+        assert_eq!(
+            graph.dependents(Path::new("/in/posts/a.md"), no_terms),
+            vec![PathBuf::from("/in/index.md")]
+        );
+        assert!(
+            graph
+                .dependents(Path::new("/in/about.md"), no_terms)
+                .is_empty()
+        );
+
+        graph.remove_page(Path::new("/in/index.md"));
+        assert!(
+            graph
+                .dependents(Path::new("/in/posts/a.md"), no_terms)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn it_finds_pages_listing_a_taxonomy() {
+        let mut graph = DependencyGraph::new();
+        graph.begin_page();
+        graph.record(Dependency::Taxonomy(
+            PathBuf::from("/in"),
+            "tags".to_string(),
+        ));
+        graph.end_page(Path::new("/in/tags.html"));
+
+        let is_tags = |name: &str| name == "tags";
+        assert_eq!(
+            graph.dependents(Path::new("/in/posts/a.md"), is_tags),
+            vec![PathBuf::from("/in/tags.html")]
+        );
+        assert!(
+            graph
+                .dependents(Path::new("/in/posts/a.md"), no_terms)
+                .is_empty()
+        );
+        assert!(
+            graph
+                .dependents(Path::new("/assets/a.md"), is_tags)
+                .is_empty()
+        );
+    }
+}
@@ -1,13 +1,50 @@
+use std::path::{Path, PathBuf};
+
 use colored::Colorize;
 
 use crate::asg::LuaEnvironment;
 
+use super::{
+    template::{Position, SourceMap},
+    tokenizer::{ParsingCache, format_location},
+};
+
 impl LuaEnvironment {
-    #[allow(clippy::print_stdout)]
+    /// Print an error and record it in the build report.
+    /// Quiet environments hold it until `print_held_errors` or `discard_held_errors`.
     pub fn display_error(&self, error_msg: &str, error_file: &str, m_error_code: Option<&str>) {
-        let config = self.config_table.borrow();
+        self.report.borrow_mut().add_error(error_file, error_msg);
+        if self.is_quiet {
+            self.held_errors.borrow_mut().push((
+                error_msg.to_string(),
+                error_file.to_string(),
+                m_error_code.map(ToString::to_string),
+            ));
+            return;
+        }
+        self.print_error(error_msg, error_file, m_error_code);
+    }
+
+    /// Print the errors held while the environment was quiet.
+    pub fn print_held_errors(&self) {
+        for (error_msg, error_file, m_error_code) in self.held_errors.take() {
+            self.print_error(&error_msg, &error_file, m_error_code.as_deref());
+        }
+    }
+
+    /// Forget the errors held while the environment was quiet, and the messages of the build
+    /// report since `message_count`, for a compilation which is done again.
+    pub fn discard_held_errors(&self, message_count: (usize, usize)) {
+        self.held_errors.borrow_mut().clear();
+        self.report
+            .borrow_mut()
+            .discard_messages_since(message_count);
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn print_error(&self, error_msg: &str, error_file: &str, m_error_code: Option<&str>) {
         // color is enabled by default.
-        let is_color = !config.contains_key("coloredErrors") || config["coloredErrors"] == "true";
+        let is_color = self.is_colored();
 
         let m_red = |s: &str| {
             if is_color { s.red() } else { s.clear() }
@@ -20,8 +57,8 @@ impl LuaEnvironment {
         if let Some(error_code) = m_error_code {
             println!("  {}", m_red("Code responsible:"));
 
-            if error_code.len() > 400 {
-                let error_code = error_code.split_at(400).0;
+            if let Some((end, _)) = error_code.char_indices().nth(400) {
+                let error_code = error_code.split_at(end).0;
                 println!("{error_code}... (omited)");
             } else {
                 println!("{error_code}");
@@ -30,4 +67,112 @@ impl LuaEnvironment {
             println!("{}", m_red("-----"));
         }
     }
+
+    /// Print an error raised by the Lua code of `error_file`. Positions in the code compiled from
+    /// templates are replaced by positions in the templates, and the line of the first one is shown.
+    pub fn display_lua_error(&self, error: &mlua::Error, error_file: &Path) {
+        let (message, position) = remap_positions(&self.cache.borrow(), &error.to_string());
+        let excerpt = position.and_then(|(file, position)| excerpt(&file, position));
+        self.display_error(&message, &error_file.to_string_lossy(), excerpt.as_deref());
+    }
+
+    /// Print an error found while parsing the template `error_file`.
+    pub fn display_template_error(&self, error_msg: &str, error_file: &Path, position: Position) {
+        let message = format!("{}: {error_msg}", format_location(error_file, position));
+        let excerpt = excerpt(error_file, position);
+        self.display_error(&message, &error_file.to_string_lossy(), excerpt.as_deref());
+    }
+}
+
+/// Replace the `file:line:` positions of Lua in `message` by `file:line:column:` positions in
+/// the templates of `cache`, and the `line N` mentioned after them, like in `(to close 'if' at line 3)`,
+/// by lines of the same template. Returns the message and the first position replaced.
+fn remap_positions(cache: &ParsingCache, message: &str) -> (String, Option<(PathBuf, Position)>) {
+    let mut remapped = String::with_capacity(message.len());
+    let mut first_position = None;
+    let mut source_map = None;
+    let mut copied = 0;
+    for (colon, _) in message.match_indices(':') {
+        if colon < copied {
+            continue;
+        }
+        let after = &message[colon + 1..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        // Positions which are already in a template have a column.
+        if digits == 0
+            || !after[digits..].starts_with(':')
+            || after[digits + 1..].starts_with(|c: char| c.is_ascii_digit())
+        {
+            continue;
+        }
+        let Ok(line) = after[..digits].parse::<usize>() else {
+            continue;
+        };
+        let before = &message[..colon];
+        let found = cache.cache.iter().find_map(|(file, partial_parse)| {
+            if !before.ends_with(file.to_string_lossy().as_ref()) {
+                return None;
+            }
+            let position = partial_parse.compiled.source_map.position(line)?;
+            Some((file.clone(), position, &partial_parse.compiled.source_map))
+        });
+        let Some((file, position, file_source_map)) = found else {
+            continue;
+        };
+        remapped.push_str(&remap_lines(&message[copied..colon], source_map));
+        remapped.push_str(&format!(":{}:{}", position.line, position.column));
+        copied = colon + 1 + digits;
+        source_map = Some(file_source_map);
+        first_position.get_or_insert((file, position));
+    }
+    remapped.push_str(&remap_lines(&message[copied..], source_map));
+    (remapped, first_position)
+}
+
+/// Replace the `line N` of the generated code mentioned in `text` by lines of the template of `source_map`.
+fn remap_lines(text: &str, source_map: Option<&SourceMap>) -> String {
+    let Some(source_map) = source_map else {
+        return text.to_string();
+    };
+    let mut remapped = String::with_capacity(text.len());
+    let mut copied = 0;
+    for (start, word) in text.match_indices("line ") {
+        let after = &text[start + word.len()..];
+        let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let Some(position) = after[..digits]
+            .parse::<usize>()
+            .ok()
+            .and_then(|line| source_map.position(line))
+        else {
+            continue;
+        };
+        remapped.push_str(&text[copied..start]);
+        remapped.push_str(&format!("line {}", position.line));
+        copied = start + word.len() + digits;
+    }
+    remapped.push_str(&text[copied..]);
+    remapped
+}
+
+/// The line of `file` at `position`, with a caret under its column.
+fn excerpt(file: &Path, position: Position) -> Option<String> {
+    let content = std::fs::read_to_string(file).ok()?;
+    let line = content.lines().nth(position.line.checked_sub(1)?)?;
+    // Point at the code rather than at the spaces before it.
+    let column = line
+        .chars()
+        .enumerate()
+        .skip(position.column.saturating_sub(1))
+        .find(|(_, c)| !c.is_whitespace())
+        .map_or(position.column.saturating_sub(1), |(i, _)| i);
+    let padding: String = line
+        .chars()
+        .take(column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let number = position.line.to_string();
+    Some(format!(
+        "{number} | {line}\n{} | {padding}^",
+        " ".repeat(number.len())
+    ))
 }
@@ -0,0 +1,176 @@
+/// Pages written with `emit_page(url, text)` or `emit_page(url, { template = path }, vars)`, in addition to
+/// the page calling it.
+/// They are compiled after the page calling it, and belong to it: they are compiled again when it is,
+/// and removed when it does not emit them anymore.
+use std::{
+    path::{Component, Path, PathBuf},
+    time,
+};
+
+use mlua::{Table, Value as LuaValue};
+
+use super::lua_environment::LuaEnvironment;
+use super::tokenizer;
+
+/// What an emitted page contains.
+#[derive(Clone)]
+pub enum EmittedContent {
+    /// Written as is.
+    Text(String),
+    /// Compiled like a page, with its layouts. The path is resolved like the path of a layout.
+    Template(PathBuf),
+}
+
+/// A page requested with `emit_page` by the page being compiled.
+#[derive(Clone)]
+pub struct EmittedPage {
+    /// Path of the page relative to the output directory, see `normalize_url`.
+    pub url: String,
+    pub content: EmittedContent,
+    /// Globals of the template.
+    pub vars: Option<Table>,
+}
+
+/// Path of an emitted page relative to the output directory, like `./tags/rust.html`.
+/// Directories, like `tags/rust/`, are written to their `index.html`.
+pub fn normalize_url(url: &str) -> Result<String, String> {
+    let path = url.trim_start_matches('/');
+    let is_outside = Path::new(path)
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if is_outside {
+        return Err(format!(
+            "Cannot emit {url}: pages are written inside the output directory"
+        ));
+    }
+    let path = path.trim_start_matches("./");
+    if path.is_empty() || path.ends_with('/') {
+        Ok(format!("./{path}index.html"))
+    } else {
+        Ok(format!("./{path}"))
+    }
+}
+
+/// Link to the page written at `url`, relative to the output directory, from any page of the site:
+/// `./tags/rust/index.html` is linked as `/tags/rust/`.
+pub fn site_url(url: &str) -> String {
+    let path = url.trim_start_matches("./").trim_start_matches('/');
+    let path = if path == "index.html" {
+        ""
+    } else {
+        path.strip_suffix("/index.html")
+            .map_or(path, |directory| &path[..directory.len() + 1])
+    };
+    format!("/{path}")
+}
+
+/// An emitted page ready to be written.
+pub struct EmittedOutput {
+    pub output_file: PathBuf,
+    pub content: String,
+    pub duration: time::Duration,
+}
+
+/// Compile the pages emitted by `page`, which was just compiled, and remove the pages
+/// it emitted during the previous build but not anymore.
+/// Returns the pages to write and whether every page could be compiled.
+pub fn compile(
+    env: &mut LuaEnvironment,
+    page: &Path,
+    base_input_directory: &Path,
+    output_directory: &Path,
+) -> (Vec<EmittedOutput>, bool) {
+    let emitted_pages = std::mem::take(&mut *env.emitted_pages.borrow_mut());
+    let mut outputs: Vec<EmittedOutput> = vec![];
+    let mut urls: Vec<String> = vec![];
+    let mut is_compiled = true;
+    for emitted_page in emitted_pages {
+        let instant_start = time::Instant::now();
+        let output_file = output_directory.join(&emitted_page.url);
+        if urls.contains(&emitted_page.url) {
+            env.report.borrow_mut().add_warning(
+                &page.to_string_lossy(),
+                &format!(
+                    "{} is emitted twice, the last one is kept",
+                    emitted_page.url
+                ),
+            );
+            outputs.retain(|output| output.output_file != output_file);
+        } else {
+            urls.push(emitted_page.url.clone());
+        }
+
+        let content = match emitted_page.content {
+            EmittedContent::Text(text) => Some(text),
+            EmittedContent::Template(template) => {
+                // Like a new page, which also sees the globals given to emit_page.
+                env.restart_page(page, base_input_directory);
+                if let Some(vars) = emitted_page.vars {
+                    for (key, value) in vars.pairs::<LuaValue, LuaValue>().flatten() {
+                        let _ = env.page_scope.raw_set(key, value);
+                    }
+                }
+                tokenizer::compile_file(env, &template, Some(&output_file), base_input_directory)
+            }
+        };
+        match content {
+            Some(content) => outputs.push(EmittedOutput {
+                output_file,
+                content,
+                duration: instant_start.elapsed(),
+            }),
+            None => {
+                is_compiled = false;
+                env.report.borrow_mut().add_error(
+                    &page.to_string_lossy(),
+                    &format!("Could not compile the emitted page {}", emitted_page.url),
+                );
+            }
+        }
+    }
+
+    remove_stale(env, page, output_directory, urls);
+    (outputs, is_compiled)
+}
+
+/// Remove the pages emitted by `page` during the previous build which are not part of `urls`,
+/// and remember `urls` as the pages it emits.
+pub fn remove_stale(env: &LuaEnvironment, page: &Path, output_directory: &Path, urls: Vec<String>) {
+    let mut cache = env.cache.borrow_mut();
+    let previous_urls = cache.emitted_pages.remove(page).unwrap_or_default();
+    for url in previous_urls {
+        if !urls.contains(&url) {
+            let _ = super::remove_output_file(&output_directory.join(url), output_directory);
+        }
+    }
+    if !urls.is_empty() {
+        cache.emitted_pages.insert(page.to_path_buf(), urls);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_normalizes_urls() {
+        assert_eq!(
+            normalize_url("/tags/rust.html").unwrap(),
+            "./tags/rust.html"
+        );
+        assert_eq!(
+            normalize_url("./tags/rust/").unwrap(),
+            "./tags/rust/index.html"
+        );
+        assert_eq!(normalize_url("").unwrap(), "./index.html");
+        assert!(normalize_url("../outside.html").is_err());
+        assert!(normalize_url("tags/../../outside.html").is_err());
+    }
+
+    #[test]
+    fn it_links_to_pages_from_the_site_root() {
+        assert_eq!(site_url("./tags/rust/index.html"), "/tags/rust/");
+        assert_eq!(site_url("./tags/rust.html"), "/tags/rust.html");
+        assert_eq!(site_url("./index.html"), "/");
+    }
+}
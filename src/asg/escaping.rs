@@ -0,0 +1,233 @@
+/// Escaping of the values of `{{ }}` tags. In HTML, markdown and XML files, values are escaped so that
+/// `<` or `&` in a title cannot break the markup. `raw(value)` marks trusted HTML, which is shown as is,
+/// and sites can turn escaping off with `setvar("autoescape", "false")`.
+/// The helpers escape values for other contexts: attributes, URLs and JavaScript strings.
+use std::path::Path;
+
+use mlua::{
+    FromLua, Function, IntoLua, Lua, MetaMethod, MultiValue, Table, UserData, UserDataMethods,
+    Value as LuaValue,
+};
+
+/// Files whose values are escaped, the other templates (CSS, JavaScript, text) are not HTML.
+pub const ESCAPED_FORMATS: &[&str] = &[".html", ".md", ".xml"];
+
+/// Text shown as is by `{{ }}`, returned by `raw` and the escaping helpers.
+/// It can be concatenated with strings, converted with `tostring`, and has the methods of strings,
+/// like `body:find("<h2")`.
+#[derive(Clone, Debug)]
+pub struct Raw(pub String);
+
+impl UserData for Raw {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::ToString, |_, raw, ()| Ok(raw.0.clone()));
+        methods.add_meta_method(MetaMethod::Len, |_, raw, ()| Ok(raw.0.len()));
+        methods.add_meta_method(MetaMethod::Index, |lua, _, name: String| {
+            let method: Option<Function> = lua.globals().get::<Table>("string")?.get(name)?;
+            match method {
+                Some(method) => Ok(Some(lua.create_function(
+                    move |_, (raw, args): (Text, MultiValue)| {
+                        method.call::<MultiValue>((raw.0, args))
+                    },
+                )?)),
+                None => Ok(None),
+            }
+        });
+        // Joining trusted values is trusted, joining them with a string is not.
+        methods.add_meta_function(
+            MetaMethod::Concat,
+            |lua, (left, right): (LuaValue, LuaValue)| {
+                let text = to_text(&left)? + &to_text(&right)?;
+                if is_raw(&left) && is_raw(&right) {
+                    Raw(text).into_lua(lua)
+                } else {
+                    text.into_lua(lua)
+                }
+            },
+        );
+    }
+}
+
+fn is_raw(value: &LuaValue) -> bool {
+    matches!(value, LuaValue::UserData(data) if data.is::<Raw>())
+}
+
+/// Argument of the functions taking HTML, which can be a string or a `Raw` value.
+pub struct Text(pub String);
+
+impl FromLua for Text {
+    fn from_lua(value: LuaValue, _: &Lua) -> mlua::Result<Self> {
+        match value {
+            LuaValue::UserData(_)
+            | LuaValue::String(_)
+            | LuaValue::Integer(_)
+            | LuaValue::Number(_) => to_text(&value).map(Text),
+            _ => Err(mlua::Error::FromLuaConversionError {
+                from: value.type_name(),
+                to: "string".to_string(),
+                message: None,
+            }),
+        }
+    }
+}
+
+/// Text of a string, a number or a `Raw` value.
+fn to_text(value: &LuaValue) -> mlua::Result<String> {
+    match value {
+        LuaValue::UserData(data) => Ok(data.borrow::<Raw>()?.0.clone()),
+        LuaValue::String(s) => Ok(s.to_string_lossy()),
+        LuaValue::Integer(i) => Ok(i.to_string()),
+        LuaValue::Number(n) => Ok(n.to_string()),
+        _ => Err(mlua::Error::runtime(format!(
+            "attempt to concatenate a {}",
+            value.type_name()
+        ))),
+    }
+}
+
+pub fn is_escaped(file_path: &Path) -> bool {
+    let name = file_path.to_string_lossy();
+    ESCAPED_FORMATS.iter().any(|format| name.ends_with(format))
+}
+
+/// Escape text inside HTML elements and quoted attributes.
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escape an attribute value, even when it is not quoted: everything but letters, digits,
+/// `-`, `_` and `.` is written as a character reference.
+pub fn escape_attr(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+            escaped.push(c);
+        } else {
+            escaped.push_str(&format!("&#x{:X};", c as u32));
+        }
+    }
+    escaped
+}
+
+/// Percent-encode a part of a URL, like a query parameter or a path segment.
+pub fn escape_url(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            escaped.push(byte as char);
+        } else {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+/// Escape text inside a JavaScript string, quoted or template literal, in a `<script>` element.
+pub fn escape_js(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            // Quotes, `${` of template literals and `</script>`.
+            '"' | '\'' | '`' | '$' | '<' | '>' | '&' | '\u{2028}' | '\u{2029}' => {
+                escaped.push_str(&format!("\\u{:04X}", c as u32));
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The function given to the code of a template to convert the values of `{{ }}` tags:
+/// `Raw` values become their text, and strings are escaped when `autoescape` is set.
+pub fn escaper(lua: &Lua, autoescape: bool) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, value: LuaValue| match value {
+        LuaValue::UserData(data) if data.is::<Raw>() => Ok(LuaValue::String(
+            lua.create_string(&data.borrow::<Raw>()?.0)?,
+        )),
+        LuaValue::String(s) if autoescape => Ok(LuaValue::String(
+            lua.create_string(escape_html(&s.to_string_lossy()))?,
+        )),
+        value => Ok(value),
+    })
+}
+
+type Escape = fn(&str) -> String;
+
+/// Register `raw` and the escaping helpers, which return `Raw` values.
+pub fn register(lua: &Lua) -> mlua::Result<()> {
+    lua.globals().set(
+        "raw",
+        lua.create_function(|_, value: LuaValue| match value {
+            LuaValue::UserData(data) if data.is::<Raw>() => Ok(data.borrow::<Raw>()?.clone()),
+            LuaValue::Nil => Ok(Raw(String::new())),
+            value => Ok(Raw(value.to_string()?)),
+        })?,
+    )?;
+    let helpers: [(&str, Escape); 4] = [
+        ("escape_html", escape_html),
+        ("escape_attr", escape_attr),
+        ("escape_url", escape_url),
+        ("escape_js", escape_js),
+    ];
+    for (name, escape) in helpers {
+        let function = lua.create_function(move |_, value: LuaValue| {
+            Ok(Raw(escape(&to_text(&value).unwrap_or_default())))
+        })?;
+        lua.globals().set(name, function)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_escapes_for_each_context() {
+        assert_eq!(
+            escape_html("<a href=\"x\">Tom & 'Jerry'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape_attr("a b=c"), "a&#x20;b&#x3D;c");
+        assert_eq!(escape_url("a b/é?"), "a%20b%2F%C3%A9%3F");
+        assert_eq!(
+            escape_js("`${x}`</script>\n\\"),
+            "\\u0060\\u0024{x}\\u0060\\u003C/script\\u003E\\n\\\\"
+        );
+    }
+
+    #[test]
+    fn it_shows_raw_values_as_is() {
+        let lua = Lua::new();
+        register(&lua).unwrap();
+        let escape = escaper(&lua, true).unwrap();
+        let render = |code: &str| -> String {
+            let value: LuaValue = lua.load(code).eval().unwrap();
+            escape.call(value).unwrap()
+        };
+        assert_eq!(render("'<b>'"), "&lt;b&gt;");
+        assert_eq!(render("raw('<b>')"), "<b>");
+        assert_eq!(render("raw('<b>') .. '<i>'"), "&lt;b&gt;&lt;i&gt;");
+        assert_eq!(render("raw('<b>') .. escape_attr('a b')"), "<b>a&#x20;b");
+        assert_eq!(render("escape_url('a b')"), "a%20b");
+        assert_eq!(render("tostring(raw(1))"), "1");
+        assert_eq!(render("raw('<h2>a</h2>'):find('<h2')"), "1");
+        assert_eq!(render("raw('<b>'):upper()"), "&lt;B&gt;");
+    }
+}
@@ -0,0 +1,152 @@
+/// Front matter: metadata written at the top of a page, between `---` lines (YAML)
+/// or `+++` lines (TOML), like other static site generators do.
+use serde_json::{Map, Value};
+
+pub type FrontMatter = Map<String, Value>;
+
+/// Extensions of the files that can start with front matter.
+pub const FRONT_MATTER_FORMATS: &[&str] = &[".md", ".html"];
+
+/// Split `content` into its front matter and the rest of the file.
+/// Returns None when there is no front matter. Only key: value pairs are front matter:
+/// `---` lines around anything else are horizontal rules of markdown, kept in the content.
+/// The number of lines of the front matter is returned so that line numbers can be fixed.
+pub fn split_front_matter(content: &str) -> (Option<FrontMatter>, &str, usize) {
+    let content_without_bom = content.strip_prefix('\u{feff}').unwrap_or(content);
+    let Some(delimiter) = ["---", "+++"]
+        .into_iter()
+        .find(|delimiter| first_line(content_without_bom) == *delimiter)
+    else {
+        return (None, content, 0);
+    };
+
+    let mut offset = first_line_length(content_without_bom);
+    let start = offset;
+    let mut line_count = 1;
+    while offset < content_without_bom.len() {
+        let line_length = first_line_length(&content_without_bom[offset..]);
+        let line = first_line(&content_without_bom[offset..]);
+        line_count += 1;
+        if line.trim_end() == delimiter {
+            let source = &content_without_bom[start..offset];
+            let body = &content_without_bom[offset + line_length..];
+            let front_matter = if delimiter == "---" {
+                parse_yaml(source)
+            } else {
+                parse_toml(source)
+            };
+            return match front_matter {
+                Some(front_matter) => (Some(front_matter), body, line_count),
+                None => (None, content, 0),
+            };
+        }
+        offset += line_length;
+    }
+    // Without a closing delimiter, `---` is a horizontal rule in markdown.
+    (None, content, 0)
+}
+
+/// First line of `s`, without the line break.
+fn first_line(s: &str) -> &str {
+    s.lines().next().unwrap_or_default()
+}
+
+/// Length of the first line of `s`, with the line break.
+fn first_line_length(s: &str) -> usize {
+    s.find('\n').map_or(s.len(), |i| i + 1)
+}
+
+/// None when `source` is not a YAML map, like the text between two horizontal rules.
+fn parse_yaml(source: &str) -> Option<FrontMatter> {
+    match serde_yaml::from_str::<Value>(source) {
+        Ok(Value::Object(front_matter)) => Some(front_matter),
+        Ok(Value::Null) => Some(FrontMatter::new()),
+        _ => None,
+    }
+}
+
+fn parse_toml(source: &str) -> Option<FrontMatter> {
+    source.parse::<toml::Table>().ok().map(|table| {
+        table
+            .into_iter()
+            .map(|(key, value)| (key, toml_to_json(value)))
+            .collect()
+    })
+}
+
+/// Dates have no JSON equivalent, they are kept as strings.
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(array) => Value::Array(array.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Convert a front matter value to the string stored by `setvar`.
+/// Lists are joined with commas, like the `tags` variable expects.
+pub fn to_config_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(to_config_value)
+            .collect::<Vec<String>>()
+            .join(","),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_yaml_front_matter() {
+        let content = "---\ntitle: Hello\ntags: [a, b]\nextra:\n  n: 1\n---\n# Body\n";
+        let (front_matter, body, line_count) = split_front_matter(content);
+        let front_matter = front_matter.unwrap();
+        assert_eq!(front_matter["title"], "Hello");
+        assert_eq!(to_config_value(&front_matter["tags"]), "a,b");
+        assert_eq!(front_matter["extra"]["n"], 1);
+        assert_eq!(body, "# Body\n");
+        assert_eq!(line_count, 6);
+    }
+
+    #[test]
+    fn it_parses_toml_front_matter() {
+        let content = "+++\r\ntitle = \"Hello\"\r\ndate = 2025-11-02\r\n+++\r\nBody";
+        let (front_matter, body, line_count) = split_front_matter(content);
+        let front_matter = front_matter.unwrap();
+        assert_eq!(front_matter["title"], "Hello");
+        assert_eq!(front_matter["date"], "2025-11-02");
+        assert_eq!(body, "Body");
+        assert_eq!(line_count, 4);
+    }
+
+    #[test]
+    fn it_keeps_files_without_front_matter() {
+        for content in [
+            "# Title\n---\n",
+            "---\nA horizontal rule, not front matter\n",
+            "---\nText between horizontal rules\n---\n",
+            "---\n- a list\n---\n",
+            "+++\nNot TOML\n+++\n",
+        ] {
+            let (front_matter, body, line_count) = split_front_matter(content);
+            assert!(front_matter.is_none());
+            assert_eq!(body, content);
+            assert_eq!(line_count, 0);
+        }
+    }
+}
@@ -86,19 +86,28 @@ fn parse_date(s: &str) -> DateTime<Local> {
         .unwrap_or_default()
 }
 
-pub fn get_git_modification_time(bi: &BlameInfo) -> DateTime<Local> {
+/// When the file at `path` was last modified on disk. Unlike the time of the build,
+/// it stays the same from one build to the next, like the dates of commits.
+pub fn get_file_modification_time(path: &Path) -> DateTime<Local> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or_else(|_| std::time::SystemTime::now())
+        .into()
+}
+
+pub fn get_git_modification_time(bi: &BlameInfo, path: &Path) -> DateTime<Local> {
     if bi.is_empty() {
-        // If the file is not in Git, it is new.
-        return std::time::SystemTime::now().into();
+        // The file is not in Git yet.
+        return get_file_modification_time(path);
     }
     let date_string = &bi[0].date;
     parse_date(date_string)
 }
 
-pub fn get_git_creation_time(bi: &BlameInfo) -> DateTime<Local> {
+pub fn get_git_creation_time(bi: &BlameInfo, path: &Path) -> DateTime<Local> {
     if bi.is_empty() {
-        // If the file is not in Git, it is new.
-        return std::time::SystemTime::now().into();
+        // The file is not in Git yet.
+        return get_file_modification_time(path);
     }
     let date_string = &bi[bi.len() - 1].date;
     parse_date(date_string)
@@ -154,4 +163,14 @@ Date:   Fri Nov 1 22:58:48 2024 +0100
         assert_eq!(commits[0].date, "Sat Feb 22 13:44:40 2025 +0100");
         assert_eq!(commits[0].message, ":bug: fix bug");
     }
+
+    #[test]
+    fn it_dates_files_outside_of_git_when_they_were_modified() {
+        let path = std::env::temp_dir().join("asg_test_git_times.md");
+        std::fs::write(&path, "Not in git").unwrap();
+        let modified: DateTime<Local> =
+            std::fs::metadata(&path).unwrap().modified().unwrap().into();
+        assert_eq!(get_git_creation_time(&vec![], &path), modified);
+        assert_eq!(get_git_modification_time(&vec![], &path), modified);
+    }
 }
@@ -1,3 +1,4 @@
+use regex::Regex;
 use scraper::{Html, Selector};
 
 #[derive(Debug, Clone)]
@@ -40,6 +41,24 @@ pub fn strip_html(s: &str) -> String {
         .to_string()
 }
 
+/// Insert a banner with `text` at the top of the body of `html`, or at the start of `html`
+/// if it has no body. Used to mark drafts so that they are not mistaken for published pages.
+pub fn add_banner(html: &str, text: &str) -> String {
+    let banner = format!(
+        "<div class=\"asg-banner\" style=\"position:sticky;top:0;z-index:1000;padding:8px;\
+         background:#f5c542;color:#000;font-family:sans-serif;text-align:center\">{text}</div>"
+    );
+    let body = Regex::new("(?i)<body[^>]*>").unwrap();
+    match body.find(html) {
+        Some(body_tag) => format!(
+            "{}{banner}{}",
+            &html[..body_tag.end()],
+            &html[body_tag.end()..]
+        ),
+        None => format!("{banner}{html}"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -58,6 +77,16 @@ mod tests {
         assert_eq!(result, "");
     }
 
+    #[test]
+    fn test_add_banner() {
+        let result = add_banner("<html><BODY class=\"a\"><p>hi</p></BODY></html>", "Draft");
+        assert!(result.starts_with("<html><BODY class=\"a\"><div class=\"asg-banner\""));
+        assert!(result.ends_with(">Draft</div><p>hi</p></BODY></html>"));
+
+        let result = add_banner("<p>hi</p>", "Draft");
+        assert!(result.starts_with("<div class=\"asg-banner\""));
+    }
+
     #[test]
     fn test_parse_html_headings() {
         let html = r#"
@@ -0,0 +1,222 @@
+/// Hooks: functions defined by config.lua that ASG calls during the build.
+/// `on_build_start()` and `on_build_end(site)` run once per build, `on_file_copied(source, destination)`
+/// after a file is copied and `on_page_rendered(page, html)` before a page is written, to change it.
+/// `on_build_end` can write files with `emit_page`, like a page.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time,
+};
+
+use mlua::{Function, IntoLuaMulti, LuaSerdeExt, Table, Value as LuaValue};
+
+use super::emitted_pages;
+use super::lua_environment::LuaEnvironment;
+
+pub const BUILD_START: &str = "on_build_start";
+pub const PAGE_RENDERED: &str = "on_page_rendered";
+pub const FILE_COPIED: &str = "on_file_copied";
+pub const BUILD_END: &str = "on_build_end";
+
+/// The pages emitted by `on_build_end` belong to this path, like the pages of a taxonomy.
+pub fn owner(input_directory: &Path) -> PathBuf {
+    input_directory.join(format!("config.lua#{BUILD_END}"))
+}
+
+/// Path of `file` relative to the output directory, as given to the hooks.
+fn relative_url(file: &Path, output_directory: &Path) -> String {
+    file.strip_prefix(output_directory)
+        .unwrap_or(file)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// The hook `name` defined by config.lua, if any.
+fn get(env: &LuaEnvironment, name: &str) -> mlua::Result<Option<Function>> {
+    match env.config_values.raw_get::<LuaValue>(name)? {
+        LuaValue::Nil => Ok(None),
+        LuaValue::Function(hook) => Ok(Some(hook)),
+        other => Err(mlua::Error::runtime(format!(
+            "{name} should be a function, not a {}",
+            other.type_name()
+        ))),
+    }
+}
+
+/// Whether config.lua defines the hook `name`. A value which is not a function is reported when called.
+fn is_defined(env: &LuaEnvironment, name: &str) -> bool {
+    !matches!(get(env, name), Ok(None))
+}
+
+/// Call the hook `name` if config.lua defines it. Errors are reported on `file`.
+fn call<R: mlua::FromLuaMulti>(
+    env: &LuaEnvironment,
+    name: &str,
+    args: impl IntoLuaMulti,
+    file: &Path,
+) -> Option<R> {
+    let result = get(env, name).and_then(|hook| match hook {
+        Some(hook) => env.with_config_globals(|| hook.call::<R>(args)).map(Some),
+        None => Ok(None),
+    });
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            env.display_error(&format!("{name}: {e}"), &file.to_string_lossy(), None);
+            None
+        }
+    }
+}
+
+/// Run `on_build_start` before any file is generated.
+pub fn build_start(env: &LuaEnvironment, input_directory: &Path) {
+    call::<()>(env, BUILD_START, (), &input_directory.join("config.lua"));
+}
+
+/// Give `html`, the content of `output_file` written for `input_file`, to `on_page_rendered`,
+/// which returns the content to write instead, or nil to keep it.
+pub fn page_rendered(
+    env: &LuaEnvironment,
+    input_file: &Path,
+    output_file: &Path,
+    output_directory: &Path,
+    html: String,
+) -> String {
+    if !is_defined(env, PAGE_RENDERED) {
+        return html;
+    }
+    let page = match page_table(env, input_file, output_file, output_directory) {
+        Ok(page) => page,
+        Err(e) => {
+            env.display_error(&e.to_string(), &input_file.to_string_lossy(), None);
+            return html;
+        }
+    };
+    call::<Option<String>>(env, PAGE_RENDERED, (page, html.as_str()), input_file)
+        .flatten()
+        .unwrap_or(html)
+}
+
+/// The metadata of `input_file`, with the `source` and `output` of the page being written.
+fn page_table(
+    env: &LuaEnvironment,
+    input_file: &Path,
+    output_file: &Path,
+    output_directory: &Path,
+) -> mlua::Result<Table> {
+    let file_info = env.cache.borrow().file_cache.get(input_file).cloned();
+    let page = match file_info {
+        Some(file_info) => match env.lua.to_value(&file_info)? {
+            LuaValue::Table(page) => page,
+            _ => env.lua.create_table()?,
+        },
+        None => env.lua.create_table()?,
+    };
+    page.set("source", input_file.to_string_lossy())?;
+    page.set("output", relative_url(output_file, output_directory))?;
+    Ok(page)
+}
+
+/// Tell `on_file_copied` that `input_file` was copied to `output_file`.
+pub fn file_copied(env: &LuaEnvironment, input_file: &Path, output_file: &Path) {
+    call::<()>(
+        env,
+        FILE_COPIED,
+        (input_file.to_string_lossy(), output_file.to_string_lossy()),
+        input_file,
+    );
+}
+
+/// Run `on_build_end` with what the build did, then write the pages it emitted.
+pub fn build_end(
+    env: &mut LuaEnvironment,
+    input_directory: &Path,
+    output_directory: &Path,
+    duration: time::Duration,
+) {
+    let owner = owner(input_directory);
+    if !is_defined(env, BUILD_END) {
+        // Files written by a hook which is gone.
+        emitted_pages::remove_stale(env, &owner, output_directory, vec![]);
+        return;
+    }
+    let instant_start = time::Instant::now();
+    env.begin_page(&owner, input_directory);
+    let site = match site_table(env, input_directory, output_directory, duration) {
+        Ok(site) => site,
+        Err(e) => {
+            env.display_error(&e.to_string(), &owner.to_string_lossy(), None);
+            return;
+        }
+    };
+    call::<()>(env, BUILD_END, site, &owner);
+
+    let (outputs, _) = emitted_pages::compile(env, &owner, input_directory, output_directory);
+    for output in outputs {
+        let content = page_rendered(
+            env,
+            &owner,
+            &output.output_file,
+            output_directory,
+            output.content,
+        );
+        if let Some(prefix) = output.output_file.parent() {
+            let _ = fs::create_dir_all(prefix);
+        }
+        match fs::write(&output.output_file, content) {
+            Ok(()) => env
+                .report
+                .borrow_mut()
+                .add_page(&output.output_file, instant_start.elapsed()),
+            Err(e) => env.report.borrow_mut().add_error(
+                &output.output_file.to_string_lossy(),
+                &format!("Could not write file: {e}"),
+            ),
+        }
+    }
+}
+
+/// What `on_build_end` receives: the published pages of the website sorted by url,
+/// the files written and copied by this build, and the number of errors and warnings.
+fn site_table(
+    env: &LuaEnvironment,
+    input_directory: &Path,
+    output_directory: &Path,
+    duration: time::Duration,
+) -> mlua::Result<Table> {
+    let data_directory = input_directory.join("data");
+    let mut file_infos: Vec<_> = env
+        .cache
+        .borrow()
+        .file_cache
+        .iter()
+        .filter(|(file, file_info)| {
+            file.starts_with(input_directory)
+                && !file.starts_with(&data_directory)
+                && file_info.is_published()
+        })
+        .map(|(_, file_info)| file_info.clone())
+        .collect();
+    file_infos.sort_by(|a, b| a.url.cmp(&b.url));
+    let pages: Vec<LuaValue> = file_infos
+        .iter()
+        .map(|file_info| env.lua.to_value(file_info))
+        .collect::<mlua::Result<_>>()?;
+
+    let report = env.report.borrow();
+    let relative_urls = |files: &[PathBuf]| -> Vec<String> {
+        files
+            .iter()
+            .map(|file| relative_url(file, output_directory))
+            .collect()
+    };
+    let site = env.lua.create_table()?;
+    site.set("pages", env.lua.create_sequence_from(pages)?)?;
+    site.set("written", relative_urls(&report.pages_written))?;
+    site.set("copied", relative_urls(&report.files_copied))?;
+    site.set("errors", report.errors.len())?;
+    site.set("warnings", report.warnings.len())?;
+    site.set("duration", duration.as_millis() as u64)?;
+    site.set("output_directory", output_directory.to_string_lossy())?;
+    Ok(site)
+}
@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Name of the file listing the files of the input directory that are not part of the website.
+pub const IGNORE_FILE_NAME: &str = ".asgignore";
+
+/// Files that are never part of a website, unless they are whitelisted with `!pattern`:
+/// hidden files (`.git`, `.DS_Store`, ...), editor swap files and node modules.
+const DEFAULT_RULES: &[&str] = &[".*", "*~", "*.swp", "*.swo", "#*#", "node_modules/"];
+
+/// Decides which files of the input directory are neither built nor watched.
+/// The rules use the gitignore syntax and come, in order of precedence, from:
+/// the `.asgignore` file, the comma separated `ignore` config variable and `DEFAULT_RULES`.
+pub struct IgnoreRules {
+    input_directory: PathBuf,
+    gitignore: Gitignore,
+}
+
+impl IgnoreRules {
+    /// Rules ignoring nothing.
+    pub fn empty(input_directory: &Path) -> IgnoreRules {
+        IgnoreRules {
+            input_directory: input_directory.to_path_buf(),
+            gitignore: Gitignore::empty(),
+        }
+    }
+
+    /// Read the rules of `input_directory`. Invalid rules are skipped and returned as errors.
+    pub fn new(input_directory: &Path, config_rules: Option<&str>) -> (IgnoreRules, Vec<String>) {
+        let mut errors = vec![];
+        let mut builder = GitignoreBuilder::new(input_directory);
+        let config_rules = config_rules.unwrap_or_default().split(',');
+        for rule in DEFAULT_RULES.iter().copied().chain(config_rules) {
+            let rule = rule.trim();
+            if rule.is_empty() {
+                continue;
+            }
+            if let Err(e) = builder.add_line(None, rule) {
+                errors.push(e.to_string());
+            }
+        }
+        let ignore_file = input_directory.join(IGNORE_FILE_NAME);
+        if ignore_file.exists()
+            && let Some(e) = builder.add(ignore_file)
+        {
+            errors.push(e.to_string());
+        }
+
+        let gitignore = builder.build().unwrap_or_else(|e| {
+            errors.push(e.to_string());
+            Gitignore::empty()
+        });
+        let rules = IgnoreRules {
+            input_directory: input_directory.to_path_buf(),
+            gitignore,
+        };
+        (rules, errors)
+    }
+
+    /// Whether `path` or one of its parent directories is ignored.
+    /// Files outside of the input directory are never ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        if !path.starts_with(&self.input_directory) || path == self.input_directory {
+            return false;
+        }
+        self.gitignore
+            .matched_path_or_any_parents(path, path.is_dir())
+            .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_ignores_hidden_and_configured_files() {
+        let input_directory = Path::new("/site");
+        let (rules, errors) =
+            IgnoreRules::new(input_directory, Some("*.draft.md, private/, !.well-known"));
+        assert!(errors.is_empty(), "{errors:?}");
+
+        assert!(rules.is_ignored(Path::new("/site/.git/config")));
+        assert!(rules.is_ignored(Path::new("/site/posts/.DS_Store")));
+        assert!(rules.is_ignored(Path::new("/site/posts/post.md.swp")));
+        assert!(rules.is_ignored(Path::new("/site/node_modules/lib/index.js")));
+        assert!(rules.is_ignored(Path::new("/site/posts/idea.draft.md")));
+        assert!(rules.is_ignored(Path::new("/site/private/notes.md")));
+
+        assert!(!rules.is_ignored(Path::new("/site/index.md")));
+        assert!(!rules.is_ignored(Path::new("/site/posts/post.md")));
+        assert!(!rules.is_ignored(Path::new("/site/.well-known/security.txt")));
+        assert!(!rules.is_ignored(Path::new("/elsewhere/.hidden")));
+    }
+}
@@ -1,23 +1,38 @@
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
     env, fs,
     path::{Path, PathBuf},
     rc::Rc,
 };
 
 use colored::Colorize;
-use mlua::{Lua, LuaSerdeExt, Value as LuaValue};
+use mlua::{FromLua, Function, Lua, Table, Thread, Value as LuaValue};
 use serde::{Deserialize, Serialize};
 
-use crate::asg::date_format::DATE_FORMAT;
+use crate::asg::date_format::{DATE_FORMAT, parse_date};
 
+use super::blocks::Blocks;
+use super::build_cache::BuildCache;
+use super::build_options::BuildOptions;
+use super::build_report::BuildReport;
 use super::buildinfo;
+use super::collections::{self, Collection, Collections, Query};
+use super::data_pages::DataPages;
+use super::dependencies::{Dependency, DependencyGraph};
+use super::emitted_pages::{self, EmittedContent, EmittedPage};
+use super::escaping::{self, Raw, Text};
+use super::front_matter::{FrontMatter, to_config_value};
+use super::git_times;
 use super::highlight_syntax;
+use super::ignore_rules::{self, IgnoreRules};
+use super::pagination::Pagination;
+use super::partials::{self, Includer, Partials};
+use super::taxonomies::{self, TaxonomyPages, Term};
 use super::{csv, handle_html, highlight_syntax::SyntaxHighlighter, tokenizer};
 
 // Information about a file accessible from the Lua script.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub filename: String,
     pub url: String,
@@ -29,12 +44,200 @@ pub struct FileInfo {
     pub title: String,
     pub description: String,
     pub tags: Vec<String>,
+    /// Set with `draft = true`. Drafts are not part of production builds.
+    #[serde(default)]
+    pub draft: bool,
+    /// Set with `publish_date = "2025-11-02"`, formatted with `DATE_FORMAT`, empty if not set.
+    /// Pages are not part of production builds before that date.
+    #[serde(default)]
+    pub publish_date: String,
+    /// Front matter keys that are not fields of `FileInfo`, available as `post.key` in Lua.
+    #[serde(flatten)]
+    pub extra: FrontMatter,
+}
+
+/// Names of the fields of `FileInfo`. Front matter keys with these names are not extra fields.
+pub const FILE_INFO_FIELDS: &[&str] = &[
+    "filename",
+    "url",
+    "size",
+    "word_count",
+    "last_modified_os",
+    "last_modified",
+    "created_at",
+    "title",
+    "description",
+    "tags",
+    "draft",
+    "publish_date",
+];
+
+/// `include`, given the function starting a partial. The code of the partial runs in Lua,
+/// so that a page waiting to list pages inside a partial can be resumed.
+const INCLUDE: &str = "local begin_include = ...
+return function(partial, vars)
+	local finish, code, output, block_marker, errors, escape = begin_include(partial, vars)
+	return finish(pcall(code, output, block_marker, errors, escape))
+end
+";
+
+/// Wraps the functions listing pages, given the function telling whether the page must wait:
+/// the page being prepared waits for the metadata of every page, see `tokenizer::compile_page_body`.
+const WAIT_BEFORE_LISTING: &str = "local should_wait = ...
+for _, name in ipairs({ \"pages\", \"posts\", \"paginate\", \"taxonomy\" }) do
+	local list = _G[name]
+	_G[name] = function(...)
+		if should_wait(coroutine.running(), coroutine.isyieldable()) then
+			coroutine.yield()
+		end
+		return list(...)
+	end
+end
+";
+
+/// Reports the reads of what the build cache cannot see, given the function recording them:
+/// the time, and the `site` table shared by pages, found by the globals of the config scope.
+/// Returns a function turning a table of globals into a scope, and a function choosing the table
+/// where the functions defined in these scopes write globals, which returns the previous one.
+const CONFIG_SCOPES: &str = "local record_unwatched = ...
+for _, name in ipairs({ \"time\", \"date\", \"clock\" }) do
+	local read_time = os[name]
+	os[name] = function(...)
+		record_unwatched()
+		return read_time(...)
+	end
+end
+local globals = _G
+local target = globals
+local function make_scope(values)
+	return setmetatable({}, {
+		__index = function(_, key)
+			if key == \"site\" then
+				record_unwatched()
+			end
+			local value = rawget(target, key)
+			if value == nil then
+				value = rawget(values, key)
+			end
+			if value == nil then
+				value = globals[key]
+			end
+			return value
+		end,
+		__newindex = function(_, key, value)
+			rawset(target, key, value)
+		end,
+	})
+end
+return make_scope, function(scope)
+	local previous = target
+	target = scope
+	return previous
+end
+";
+
+impl FileInfo {
+    pub fn publish_time(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        parse_date(&self.publish_date)
+    }
+
+    /// Date of the page: its `publish_date`, its `date` front matter key, or when it was added to git.
+    /// Unlike `publish_date`, `date` does not schedule the page.
+    pub fn date(&self) -> Option<chrono::DateTime<chrono::Local>> {
+        self.publish_time()
+            .or_else(|| {
+                self.extra
+                    .get("date")
+                    .and_then(|date| parse_date(&to_config_value(date)))
+            })
+            .or_else(|| parse_date(&self.created_at))
+    }
+
+    /// Whether the page is neither a draft nor scheduled for later.
+    pub fn is_published(&self) -> bool {
+        !self.draft
+            && self
+                .publish_time()
+                .is_none_or(|time| time <= chrono::Local::now())
+    }
+
+    /// Text of the banner shown on unpublished pages outside of production builds.
+    pub fn unpublished_label(&self) -> String {
+        if self.draft {
+            "Draft".to_string()
+        } else {
+            format!("Scheduled for {}", self.publish_date)
+        }
+    }
+}
+
+/// Globals, `setvar` values and dependencies of a page whose compilation is not finished.
+pub struct SuspendedPage {
+    page_scope: Table,
+    config_writes: Table,
+    blocks: Blocks,
+    config: HashMap<String, String>,
+    pagination: Pagination,
+    emitted_pages: Vec<EmittedPage>,
+    dependencies: BTreeSet<Dependency>,
+    /// Partials the page waits in.
+    partials: Vec<PathBuf>,
 }
 
 pub struct LuaEnvironment {
     pub lua: Lua,
+    pub assets_path: Rc<PathBuf>,
     pub config_table: Rc<RefCell<HashMap<String, String>>>,
+    /// `setvar` values before config.lua ran (command line overrides, std.lua).
+    base_config: HashMap<String, String>,
+    /// `setvar` values right after config.lua ran. Every page starts from them.
+    config_snapshot: HashMap<String, String>,
+    /// Globals defined by config.lua and its hooks.
+    pub config_values: Table,
+    /// Globals of config.lua: reads look in `config_writes`, then `config_values`, then the real globals.
+    config_scope: Table,
+    /// Globals of std.lua, which defines its functions in the real globals.
+    std_scope: Table,
+    /// Turns a table of globals into a scope, see `CONFIG_SCOPES`.
+    make_config_scope: Function,
+    /// Chooses where the functions of std.lua and config.lua write globals, see `CONFIG_SCOPES`.
+    set_globals_target: Function,
+    /// Globals written by the functions of std.lua and config.lua while the page is compiled,
+    /// so that every page starts from the globals defined by config.lua.
+    config_writes: Table,
+    /// Globals of the page being compiled. Reads fall back to `config_scope`,
+    /// so that pages cannot see the globals of the pages compiled before them.
+    pub page_scope: Table,
     pub cache: Rc<RefCell<tokenizer::ParsingCache>>,
+    pub report: Rc<RefCell<BuildReport>>,
+    pub dependencies: Rc<RefCell<DependencyGraph>>,
+    pub build_cache: BuildCache,
+    /// Files of the input directory that are not part of the website. Read with config.lua.
+    pub ignore_rules: IgnoreRules,
+    pub options: BuildOptions,
+    /// Collections declared by config.lua.
+    pub collections: Rc<RefCell<Collections>>,
+    /// Which page of a paginated list is being compiled.
+    pub pagination: Rc<RefCell<Pagination>>,
+    /// Pages requested with `emit_page` by the page being compiled.
+    pub emitted_pages: Rc<RefCell<Vec<EmittedPage>>>,
+    /// Data files generating a page per row, declared by config.lua.
+    pub data_pages: Rc<RefCell<Vec<DataPages>>>,
+    /// Taxonomies with a page per term, declared by config.lua.
+    pub taxonomy_pages: Rc<RefCell<Vec<TaxonomyPages>>>,
+    /// Blocks filled by the page being compiled and its layouts.
+    pub blocks: Blocks,
+    /// Partials being rendered with `include`.
+    pub partials: Rc<RefCell<Partials>>,
+    /// Errors are held while set, for compilations that may be done again.
+    pub is_quiet: bool,
+    /// Errors held while `is_quiet` was set: message, file and code responsible.
+    pub held_errors: RefCell<Vec<(String, String, Option<String>)>>,
+    /// The thread running the body of the page being prepared, which waits before listing pages.
+    pub preparing_thread: Rc<RefCell<Option<Thread>>>,
+    /// Whether the page being prepared listed pages where it could not wait, like inside a function
+    /// called by `table.sort`. The list can miss pages, the page is compiled again.
+    pub is_listed_early: Rc<Cell<bool>>,
 }
 
 pub fn get_exe_dir_path() -> PathBuf {
@@ -51,22 +254,78 @@ impl LuaEnvironment {
     pub fn new(
         input_directory: &Path,
         _output_directory: &Path,
-        asset_directory: Option<PathBuf>,
+        options: &BuildOptions,
     ) -> LuaEnvironment {
         // In a testing environment, there may not be an asset folder next to the executable, so we use the asset_directory provided.
-        let assets_path = Rc::new(asset_directory.unwrap_or(get_asset_dir_path()));
+        let assets_path = Rc::new(
+            options
+                .asset_directory
+                .clone()
+                .unwrap_or(get_asset_dir_path()),
+        );
 
         let lua = Lua::new();
         let _ = lua.sandbox(false);
         lua.enable_jit(true);
 
-        let config_table = Rc::new(RefCell::new(HashMap::new()));
+        // Values from the command line are set first and cannot be changed by setvar.
+        let config_overrides: HashMap<String, String> =
+            options.config_overrides.iter().cloned().collect();
+        let config_table = Rc::new(RefCell::new(config_overrides.clone()));
         let cache = Rc::new(RefCell::new(tokenizer::ParsingCache::new()));
+        let report = Rc::new(RefCell::new(BuildReport::new()));
+        let dependencies = Rc::new(RefCell::new(DependencyGraph::new()));
+        let mut build_cache = BuildCache::new(options);
+        build_cache.load(&mut cache.borrow_mut(), &mut dependencies.borrow_mut());
+
+        let dependencies_ref = dependencies.clone();
+        let record_unwatched = lua
+            .create_function(move |_, ()| {
+                dependencies_ref.borrow_mut().record(Dependency::Unwatched);
+                Ok(())
+            })
+            .unwrap();
+        let (make_config_scope, set_globals_target): (Function, Function) = lua
+            .load(CONFIG_SCOPES)
+            .set_name("=asg")
+            .call(record_unwatched)
+            .unwrap();
+        let config_values = lua.create_table().unwrap();
+        let config_scope: Table = make_config_scope.call(config_values.clone()).unwrap();
+        let std_scope: Table = make_config_scope.call(lua.globals()).unwrap();
+        let config_writes = lua.create_table().unwrap();
+        let page_scope = create_scope(&lua, config_scope.clone());
 
-        let env = LuaEnvironment {
+        let mut env = LuaEnvironment {
             lua,
+            assets_path: assets_path.clone(),
             config_table,
+            base_config: HashMap::new(),
+            config_snapshot: HashMap::new(),
+            config_values,
+            config_scope,
+            std_scope,
+            make_config_scope,
+            set_globals_target,
+            config_writes,
+            page_scope,
             cache,
+            report,
+            dependencies,
+            build_cache,
+            ignore_rules: IgnoreRules::empty(input_directory),
+            options: options.clone(),
+            collections: Rc::new(RefCell::new(Collections::default())),
+            pagination: Rc::new(RefCell::new(Pagination::default())),
+            emitted_pages: Rc::new(RefCell::new(vec![])),
+            data_pages: Rc::new(RefCell::new(vec![])),
+            taxonomy_pages: Rc::new(RefCell::new(vec![])),
+            blocks: Blocks::default(),
+            partials: Rc::new(RefCell::new(Partials::default())),
+            is_quiet: false,
+            held_errors: RefCell::new(vec![]),
+            preparing_thread: Rc::new(RefCell::new(None)),
+            is_listed_early: Rc::new(Cell::new(false)),
         };
 
         let table_ref = env.config_table.clone();
@@ -78,7 +337,9 @@ impl LuaEnvironment {
                 env.lua
                     .create_function(move |_, (key, value): (String, String)| {
                         // println!("setvar: Setting {} to {}", key, value);
-                        table_ref.borrow_mut().insert(key, value);
+                        if !config_overrides.contains_key(&key) {
+                            table_ref.borrow_mut().insert(key, value);
+                        }
                         Ok(())
                     })
                     .unwrap(),
@@ -86,6 +347,7 @@ impl LuaEnvironment {
             .unwrap();
 
         let assets_path_ref = assets_path.clone();
+        let dependencies_ref = env.dependencies.clone();
         env.lua
             .globals()
             .set(
@@ -93,16 +355,74 @@ impl LuaEnvironment {
                 env.lua
                     .create_function(move |_, asset_path: String| {
                         let asset_path = assets_path_ref.join(Path::new(&asset_path));
+                        dependencies_ref
+                            .borrow_mut()
+                            .record(Dependency::File(asset_path.clone()));
                         if asset_path.exists() {
-                            Ok(fs::read_to_string(asset_path).unwrap_or_default())
+                            Ok(Raw(fs::read_to_string(asset_path).unwrap_or_default()))
                         } else {
-                            Ok(String::new())
+                            Ok(Raw(String::new()))
                         }
                     })
                     .unwrap(),
             )
             .unwrap();
 
+        let partials_ref = env.partials.clone();
+        let config_ref = env.config_table.clone();
+        let cache_ref = env.cache.clone();
+        let dependencies_ref = env.dependencies.clone();
+        let assets_path_ref = assets_path.clone();
+        let input_directory_ref = input_directory.to_path_buf();
+        let begin_include = env
+            .lua
+            .create_function(move |lua, (partial, vars): (String, Option<Table>)| {
+                let includer = Includer {
+                    partials: &partials_ref,
+                    cache: &cache_ref,
+                    dependencies: &dependencies_ref,
+                    input_directory: &input_directory_ref,
+                    assets_path: &assets_path_ref,
+                    autoescape: config_ref
+                        .borrow()
+                        .get("autoescape")
+                        .is_none_or(|value| value != "false"),
+                };
+                let started = includer.start_include(lua, &partial, vars)?;
+                let code = started.code.clone();
+                let (output, block_marker, errors, escape) = started.args();
+                let partials_ref = partials_ref.clone();
+                let mut started = Some(started);
+                let finish =
+                    lua.create_function_mut(move |lua, (is_ok, result): (bool, LuaValue)| {
+                        let Some(started) = started.take() else {
+                            return Err(mlua::Error::runtime("The partial is already rendered"));
+                        };
+                        let result = if is_ok {
+                            usize::from_lua(result, lua)
+                        } else {
+                            Err(mlua::Error::from_lua(result, lua)?)
+                        };
+                        // The partial escaped its values, its HTML is shown as is.
+                        partials::finish_include(&partials_ref, started, result).map(Raw)
+                    })?;
+                Ok((finish, code, output, block_marker, errors, escape))
+            })
+            .unwrap();
+        env.lua
+            .globals()
+            .set(
+                "include",
+                env.lua
+                    .load(INCLUDE)
+                    .set_name("=asg")
+                    .call::<Function>(begin_include)
+                    .unwrap(),
+            )
+            .unwrap();
+
+        escaping::register(&env.lua).unwrap();
+
         env.lua
             .globals()
             .set(
@@ -113,61 +433,271 @@ impl LuaEnvironment {
             )
             .unwrap();
 
-        let posts_path = Rc::new(input_directory.join("posts"));
+        let collections_ref = env.collections.clone();
+        env.lua
+            .globals()
+            .set(
+                "collection",
+                env.lua
+                    .create_function(move |_, (name, options): (String, Option<Table>)| {
+                        let collection = Collection::from_lua(&name, options.as_ref())?;
+                        collections_ref.borrow_mut().add(collection);
+                        Ok(())
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let data_pages_ref = env.data_pages.clone();
+        let data_path = input_directory.join("data");
+        env.lua
+            .globals()
+            .set(
+                "data_pages",
+                env.lua
+                    .create_function(move |_, (data_file, options): (String, Table)| {
+                        let data_pages = DataPages::from_lua(&data_path, &data_file, &options)?;
+                        // A data file generates the pages of its last declaration.
+                        let mut declared = data_pages_ref.borrow_mut();
+                        declared.retain(|d| d.data_file != data_pages.data_file);
+                        declared.push(data_pages);
+                        Ok(())
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+
         let cache_ref = env.cache.clone();
+        let dependencies_ref = env.dependencies.clone();
+        let collections_ref = env.collections.clone();
+        let input_directory_ref = input_directory.to_path_buf();
+        let list_pages = Rc::new(
+            move |lua: &Lua, name: &str, query: Option<Table>| -> mlua::Result<Vec<LuaValue>> {
+                let Some(collection) = collections_ref.borrow().get(name) else {
+                    return Err(mlua::Error::runtime(format!("Unknown collection {name}")));
+                };
+                let directory = input_directory_ref.join(&collection.directory);
+                // A new, modified or removed page changes the list.
+                dependencies_ref
+                    .borrow_mut()
+                    .record(Dependency::Directory(directory.clone()));
+                let query = Query::from_lua(&collection, query.as_ref())?;
+                // Every page knows its metadata before any page is rendered.
+                let file_infos =
+                    collections::list(&directory, &cache_ref.borrow().file_cache, &query);
+                collections::to_lua(lua, file_infos, query.filter.as_ref(), query.limit)
+            },
+        );
+        let iterate = |lua: &Lua, pages: Vec<LuaValue>| {
+            let mut pages = pages.into_iter();
+            lua.create_function_mut(move |_, ()| Ok(pages.next().unwrap_or(LuaValue::Nil)))
+        };
+
+        let list_pages_ref = list_pages.clone();
+        env.lua
+            .globals()
+            .set(
+                "pages",
+                env.lua
+                    .create_function(move |lua: &Lua, (name, query): (String, Option<Table>)| {
+                        iterate(lua, list_pages_ref(lua, &name, query)?)
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
 
-        let posts_path_ref = posts_path.clone();
+        let list_pages_ref = list_pages.clone();
         env.lua
             .globals()
             .set(
                 "posts",
                 env.lua
-                    .create_function(move |lua: &Lua, ()| {
-                        if !posts_path_ref.exists() {
-                            let iterator_nil =
-                                lua.create_function(|_, ()| Ok(LuaValue::Nil)).unwrap();
-                            return Ok(iterator_nil);
+                    .create_function(move |lua: &Lua, query: Option<Table>| {
+                        iterate(lua, list_pages_ref(lua, collections::POSTS, query)?)
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let taxonomy_pages_ref = env.taxonomy_pages.clone();
+        env.lua
+            .globals()
+            .set(
+                "taxonomy_pages",
+                env.lua
+                    .create_function(move |_, (name, options): (String, Table)| {
+                        let taxonomy_pages = TaxonomyPages::from_lua(&name, &options)?;
+                        let mut declared = taxonomy_pages_ref.borrow_mut();
+                        declared.retain(|t| t.name != name);
+                        declared.push(taxonomy_pages);
+                        Ok(())
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let cache_ref = env.cache.clone();
+        let dependencies_ref = env.dependencies.clone();
+        let taxonomy_pages_ref = env.taxonomy_pages.clone();
+        let input_directory_ref = input_directory.to_path_buf();
+        env.lua
+            .globals()
+            .set(
+                "taxonomy",
+                env.lua
+                    .create_function(move |lua: &Lua, (name, query): (String, Option<Table>)| {
+                        // Any page can use a term.
+                        dependencies_ref.borrow_mut().record(Dependency::Taxonomy(
+                            input_directory_ref.clone(),
+                            name.clone(),
+                        ));
+                        let query = taxonomies::query_from_lua(query.as_ref())?;
+                        let terms = taxonomies::terms(
+                            &name,
+                            &input_directory_ref,
+                            &cache_ref.borrow().file_cache,
+                            &query,
+                        );
+                        let taxonomy_pages = taxonomy_pages_ref
+                            .borrow()
+                            .iter()
+                            .find(|t| t.name == name)
+                            .cloned();
+                        let terms =
+                            taxonomies::to_lua(lua, terms, taxonomy_pages.as_ref(), &query)?;
+                        lua.create_sequence_from(terms)
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let cache_ref = env.cache.clone();
+        let dependencies_ref = env.dependencies.clone();
+        let taxonomy_pages_ref = env.taxonomy_pages.clone();
+        let input_directory_ref = input_directory.to_path_buf();
+        env.lua
+            .globals()
+            .set(
+                "taxonomy_url",
+                env.lua
+                    .create_function(move |_, (name, term): (String, Option<String>)| {
+                        let declared = taxonomy_pages_ref.borrow();
+                        let Some(taxonomy_pages) = declared.iter().find(|t| t.name == name) else {
+                            return Ok(None);
+                        };
+                        if term.is_none() && taxonomy_pages.index_template.is_none() {
+                            return Ok(None);
                         }
+                        // The slug of a term depends on the other terms.
+                        dependencies_ref.borrow_mut().record(Dependency::Taxonomy(
+                            input_directory_ref.clone(),
+                            name.clone(),
+                        ));
+                        let term = term.map(|term| Term {
+                            slug: taxonomies::slug(
+                                &name,
+                                &term,
+                                &input_directory_ref,
+                                &cache_ref.borrow().file_cache,
+                            ),
+                            name: term,
+                            pages: vec![],
+                        });
+                        taxonomy_pages
+                            .page_url(term.as_ref())
+                            .map(|url| Some(emitted_pages::site_url(&url)))
+                            .map_err(mlua::Error::runtime)
+                    })
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let pagination_ref = env.pagination.clone();
+        env.lua
+            .globals()
+            .set(
+                "paginate",
+                env.lua
+                    .create_function(
+                        move |lua: &Lua, (name, size, query): (String, usize, Option<Table>)| {
+                            let pages = list_pages(lua, &name, query)?;
+                            pagination_ref.borrow_mut().paginator(lua, pages, size)
+                        },
+                    )
+                    .unwrap(),
+            )
+            .unwrap();
 
-                        let cache_ref = cache_ref.clone();
+        let preparing_thread_ref = env.preparing_thread.clone();
+        let is_listed_early_ref = env.is_listed_early.clone();
+        let should_wait = env
+            .lua
+            .create_function(move |_, (thread, is_yieldable): (Option<Thread>, bool)| {
+                let Some(preparing_thread) = preparing_thread_ref.borrow().clone() else {
+                    return Ok(false);
+                };
+                if is_yieldable && thread.as_ref() == Some(&preparing_thread) {
+                    return Ok(true);
+                }
+                is_listed_early_ref.set(true);
+                Ok(false)
+            })
+            .unwrap();
+        env.lua
+            .load(WAIT_BEFORE_LISTING)
+            .set_name("=asg")
+            .call::<()>(should_wait)
+            .unwrap();
 
-                        let walker =
-                            walkdir::WalkDir::new(posts_path_ref.as_path()).sort_by(|a, b| {
-                                let t1 = a.metadata().ok().and_then(|meta| meta.modified().ok());
-                                let t2 = b.metadata().ok().and_then(|meta| meta.modified().ok());
-                                match (t1, t2) {
-                                    (Some(t1), Some(t2)) => t1.cmp(&t2),
-                                    _ => std::cmp::Ordering::Equal,
-                                }
-                            });
-                        let iterator = Rc::new(RefCell::new(walker.into_iter()));
-
-                        let iterator_fn = lua
-                            .create_function(move |lua: &Lua, ()| {
-                                loop {
-                                    let Some(Ok(entry)) = iterator.borrow_mut().next() else {
-                                        return Ok(LuaValue::Nil);
-                                    };
-                                    let cache_ref = cache_ref.clone();
-                                    let cache_ref = cache_ref.borrow();
-                                    let path = entry.into_path();
-                                    let Some(file_info) = cache_ref.file_cache.get(&path) else {
-                                        continue;
-                                    };
-                                    let Ok(value) = lua.to_value(&file_info) else {
-                                        continue;
+        let emitted_pages_ref = env.emitted_pages.clone();
+        let input_directory_ref = input_directory.to_path_buf();
+        let assets_path_ref = assets_path.clone();
+        env.lua
+            .globals()
+            .set(
+                "emit_page",
+                env.lua
+                    .create_function(
+                        move |lua, (url, content, vars): (String, LuaValue, Option<Table>)| {
+                            let url =
+                                emitted_pages::normalize_url(&url).map_err(mlua::Error::runtime)?;
+                            // Templates are explicit, so that a page containing a file name is not compiled.
+                            let content = match content {
+                                LuaValue::Table(options) => {
+                                    let Some(template) =
+                                        options.get::<Option<String>>("template")?
+                                    else {
+                                        return Err(mlua::Error::runtime(
+                                            "emit_page needs text or { template = path }",
+                                        ));
                                     };
-                                    return Ok(value);
+                                    if tokenizer::resolve_path(
+                                        Path::new(&template),
+                                        &input_directory_ref,
+                                        &assets_path_ref,
+                                    )
+                                    .is_none()
+                                    {
+                                        return Err(mlua::Error::runtime(format!(
+                                            "Template {template} not found"
+                                        )));
+                                    }
+                                    EmittedContent::Template(PathBuf::from(template))
                                 }
-                            })
-                            .unwrap();
-                        Ok(iterator_fn)
-                    })
+                                text => EmittedContent::Text(Text::from_lua(text, lua)?.0),
+                            };
+                            emitted_pages_ref
+                                .borrow_mut()
+                                .push(EmittedPage { url, content, vars });
+                            Ok(())
+                        },
+                    )
                     .unwrap(),
             )
             .unwrap();
 
         let data_path = input_directory.join("data");
+        let dependencies_ref = env.dependencies.clone();
         env.lua
             .globals()
             .set(
@@ -176,6 +706,9 @@ impl LuaEnvironment {
                     .create_function(move |_, filename: String| {
                         let data_path = data_path.as_path();
                         let data_file = data_path.join(filename);
+                        dependencies_ref
+                            .borrow_mut()
+                            .record(Dependency::File(data_file.clone()));
                         if data_file.exists() {
                             Ok(fs::read_to_string(data_file).unwrap_or_default())
                         } else {
@@ -187,7 +720,7 @@ impl LuaEnvironment {
             .unwrap();
 
         let data_path = input_directory.join("data");
-
+        let dependencies_ref = env.dependencies.clone();
         env.lua
             .globals()
             .set(
@@ -196,6 +729,9 @@ impl LuaEnvironment {
                     .create_function(move |_, filename: String| {
                         let data_path = data_path.as_path();
                         let data_file = data_path.join(filename);
+                        dependencies_ref
+                            .borrow_mut()
+                            .record(Dependency::File(data_file.clone()));
                         Ok(csv::read_csv_file(&data_file))
                     })
                     .unwrap(),
@@ -203,6 +739,7 @@ impl LuaEnvironment {
             .unwrap();
 
         let cache_ref = env.cache.clone();
+        let dependencies_ref = env.dependencies.clone();
         env.lua
             .globals()
             .set(
@@ -215,6 +752,9 @@ impl LuaEnvironment {
                                 == Some(filename.clone())
                                 && let Ok(content) = std::fs::read_to_string(path)
                             {
+                                dependencies_ref
+                                    .borrow_mut()
+                                    .record(Dependency::File(path.clone()));
                                 return Ok(content);
                             }
                         }
@@ -231,7 +771,7 @@ impl LuaEnvironment {
             .set(
                 "parse_html",
                 env.lua
-                    .create_function(move |lua, html: String| {
+                    .create_function(move |lua, Text(html): Text| {
                         let headings = handle_html::parse_html(&html);
                         let result_table = lua.create_table()?;
 
@@ -257,7 +797,7 @@ impl LuaEnvironment {
                     .create_function(move |_, (code, lang): (String, String)| {
                         let sh = SyntaxHighlighter::new();
                         let html = highlight_syntax::highlight_syntax(&sh, &code, &lang);
-                        Ok(html)
+                        Ok(Raw(html))
                     })
                     .unwrap(),
             )
@@ -298,11 +838,21 @@ impl LuaEnvironment {
             )
             .unwrap();
 
+        if options.production {
+            env.lua.globals().set("production", true).unwrap();
+        }
+
+        // Pages can share state on purpose using the site table.
+        env.lua
+            .globals()
+            .set("site", env.lua.create_table().unwrap())
+            .unwrap();
+
         // Let's run std.lua if it exists
         let std_file = assets_path.join("std.lua");
         #[allow(clippy::print_stdout)]
         if std_file.exists() {
-            env.run_file_and_display_error(&std_file);
+            env.run_std(&std_file);
         } else {
             println!(
                 "{}: std.lua not found in assets directory. Check your installation.",
@@ -316,24 +866,284 @@ impl LuaEnvironment {
             std::process::exit(1);
         }
 
+        env.base_config = env.config_table.borrow().clone();
+        env.config_snapshot = env.base_config.clone();
         env
     }
 
+    /// Run the config.lua of the input directory if it exists.
+    /// It only needs to run once per build and again when it changes.
+    #[allow(clippy::print_stdout)]
+    pub fn load_config(&mut self, input_directory: &Path) {
+        let config_file = input_directory.join("config.lua");
+        if config_file.exists() {
+            self.run_config(&config_file);
+        } else {
+            println!(
+                "Warning: Config file not at {}",
+                config_file.to_string_lossy()
+            );
+            self.report
+                .borrow_mut()
+                .add_warning(&config_file.to_string_lossy(), "Config file not found");
+        }
+
+        let (ignore_rules, errors) =
+            IgnoreRules::new(input_directory, self.get_config("ignore").as_deref());
+        let ignore_file = input_directory.join(ignore_rules::IGNORE_FILE_NAME);
+        for error in errors {
+            println!("Warning: Invalid ignore rule: {error}");
+            self.report.borrow_mut().add_warning(
+                &ignore_file.to_string_lossy(),
+                &format!("Invalid ignore rule: {error}"),
+            );
+        }
+        self.ignore_rules = ignore_rules;
+    }
+
+    /// Run config.lua in a new config scope and remember the `setvar` values it defined.
+    fn run_config(&mut self, config_file: &Path) {
+        *self.config_table.borrow_mut() = self.base_config.clone();
+        self.collections.borrow_mut().clear();
+        self.data_pages.borrow_mut().clear();
+        self.taxonomy_pages.borrow_mut().clear();
+        self.config_values = self.lua.create_table().unwrap();
+        self.config_scope = self
+            .make_config_scope
+            .call(self.config_values.clone())
+            .unwrap();
+        self.with_config_globals(|| {
+            self.run_file_in_scope(config_file, self.config_scope.clone());
+        });
+        self.config_snapshot = self.config_table.borrow().clone();
+    }
+
+    /// Start compiling `page`: globals and `setvar` values go back to
+    /// what they were right after config.lua ran. Pages of a collection start with its layout.
+    pub fn begin_page(&mut self, page: &Path, input_directory: &Path) {
+        self.restart_page(page, input_directory);
+        self.emitted_pages.borrow_mut().clear();
+        self.dependencies.borrow_mut().begin_page();
+    }
+
+    /// Compile `page` again from the start, keeping the dependencies recorded since `begin_page`.
+    pub fn restart_page(&mut self, page: &Path, input_directory: &Path) {
+        *self.pagination.borrow_mut() =
+            Pagination::new(&self.destination_url(page, input_directory));
+        *self.config_table.borrow_mut() = self.config_snapshot.clone();
+        let is_layout_overridden = self
+            .options
+            .config_overrides
+            .iter()
+            .any(|(k, _)| k == "layout");
+        if let Some(layout) = self
+            .collections
+            .borrow()
+            .containing(page, input_directory)
+            .and_then(|collection| collection.layout.clone())
+            && !is_layout_overridden
+        {
+            self.config_table
+                .borrow_mut()
+                .insert("layout".to_string(), layout);
+        }
+        self.config_writes = self.lua.create_table().unwrap();
+        self.set_globals_target(self.config_writes.clone());
+        self.page_scope = create_scope(&self.lua, self.config_scope.clone());
+        self.blocks = Blocks::default();
+        self.partials.borrow_mut().page_scope = Some(self.page_scope.clone());
+    }
+
+    /// The data pages written by `page`, when it is the owner of data pages declared by config.lua.
+    pub fn data_pages_of(&self, page: &Path, input_directory: &Path) -> Option<DataPages> {
+        self.data_pages
+            .borrow()
+            .iter()
+            .find(|data_pages| data_pages.owner(input_directory) == page)
+            .cloned()
+    }
+
+    /// The taxonomy pages written by `page`, when it is the owner of taxonomy pages declared by config.lua.
+    pub fn taxonomy_pages_of(&self, page: &Path, input_directory: &Path) -> Option<TaxonomyPages> {
+        self.taxonomy_pages
+            .borrow()
+            .iter()
+            .find(|taxonomy_pages| taxonomy_pages.owner(input_directory) == page)
+            .cloned()
+    }
+
+    /// Path of the output of `page` relative to the output directory, like `./posts/a.html`.
+    /// Pages of a collection with a permalink pattern are written where it says.
+    pub fn destination_url(&self, page: &Path, input_directory: &Path) -> String {
+        let collections = self.collections.borrow();
+        let Some((collection, pattern)) = collections
+            .containing(page, input_directory)
+            .and_then(|c| c.permalink.as_ref().map(|pattern| (c, pattern)))
+        else {
+            return tokenizer::get_destination_url(page, input_directory);
+        };
+
+        let front_matter = tokenizer::tokenize_file(
+            &mut self.cache.borrow_mut(),
+            page,
+            input_directory,
+            &self.assets_path,
+        )
+        .and_then(|partial_parse| partial_parse.front_matter().cloned())
+        .unwrap_or_default();
+        let slug = front_matter.get("slug").map_or_else(
+            || {
+                page.file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string()
+            },
+            to_config_value,
+        );
+        let date = ["publish_date", "date"]
+            .iter()
+            .filter_map(|key| front_matter.get(*key))
+            .find_map(|date| parse_date(&to_config_value(date)))
+            .or_else(|| parse_date(&self.cache.borrow_mut().git_times(page).created_at))
+            .unwrap_or_else(|| git_times::get_file_modification_time(page));
+
+        let directory = input_directory.join(&collection.directory);
+        let relative_path = page.strip_prefix(&directory).unwrap_or(page);
+        let output_path = collections::expand_permalink(pattern, relative_path, &slug, date);
+        format!("./{output_path}")
+    }
+
+    /// Remember what the page compiled since `begin_page` depends on.
+    pub fn end_page(&mut self, page: &Path) {
+        self.dependencies.borrow_mut().end_page(page);
+    }
+
+    /// Put the page being compiled aside, so that other pages can be compiled
+    /// before it is finished with `resume_page`.
+    pub fn suspend_page(&mut self) -> SuspendedPage {
+        SuspendedPage {
+            page_scope: self.page_scope.clone(),
+            config_writes: self.config_writes.clone(),
+            blocks: self.blocks.clone(),
+            config: self.config_table.borrow().clone(),
+            pagination: self.pagination.borrow().clone(),
+            emitted_pages: self.emitted_pages.take(),
+            dependencies: self.dependencies.borrow_mut().suspend_page(),
+            partials: std::mem::take(&mut self.partials.borrow_mut().recursion_path),
+        }
+    }
+
+    /// Continue compiling a page put aside by `suspend_page`.
+    pub fn resume_page(&mut self, page: SuspendedPage) {
+        *self.config_table.borrow_mut() = page.config;
+        *self.pagination.borrow_mut() = page.pagination;
+        *self.emitted_pages.borrow_mut() = page.emitted_pages;
+        self.page_scope = page.page_scope;
+        self.config_writes = page.config_writes;
+        self.set_globals_target(self.config_writes.clone());
+        self.blocks = page.blocks;
+        let mut partials = self.partials.borrow_mut();
+        partials.page_scope = Some(self.page_scope.clone());
+        partials.recursion_path = page.partials;
+        self.dependencies
+            .borrow_mut()
+            .resume_page(page.dependencies);
+    }
+
+    /// Start building after files changed. Every page is compiled again when
+    /// config.lua or std.lua changed since the last build.
+    pub fn begin_build(&mut self, input_directory: &Path) {
+        let config_files = [
+            input_directory.join("config.lua"),
+            self.assets_path.join("std.lua"),
+        ];
+        let publish_times: Vec<i64> = self
+            .cache
+            .borrow()
+            .file_cache
+            .values()
+            .filter_map(|file_info| file_info.publish_time())
+            .map(|time| time.timestamp())
+            .collect();
+        self.build_cache.begin_build(&config_files, &publish_times);
+    }
+
+    /// Write the cache to the cache directory so that the next run can reuse it.
+    #[allow(clippy::print_stdout)]
+    pub fn save_cache(&self) {
+        let result = self
+            .build_cache
+            .save(&self.cache.borrow(), &self.dependencies.borrow());
+        if let Err(e) = result {
+            println!("Warning: Could not save the cache because {e}");
+        }
+    }
+
+    /// Re-run std.lua after it was modified. Functions it defines are replaced.
+    pub fn reload_std(&self) {
+        let std_file = self.assets_path.join("std.lua");
+        if std_file.exists() {
+            self.run_std(&std_file);
+        }
+    }
+
     pub fn is_enabled(&self, feature_name: &str) -> bool {
         let config = self.config_table.borrow();
         config.contains_key(feature_name) && config.get(feature_name).unwrap() == "true"
     }
 
+    /// Errors are colored unless `coloredErrors` is set to something else than "true".
+    pub fn is_colored(&self) -> bool {
+        self.get_config("coloredErrors")
+            .is_none_or(|value| value == "true")
+    }
+
     pub fn get_config(&self, key: &str) -> Option<String> {
         let config = self.config_table.borrow();
         config.get(key).cloned()
     }
 
+    /// Return everything reported since the last call and start a new report.
+    pub fn take_report(&self) -> BuildReport {
+        std::mem::take(&mut *self.report.borrow_mut())
+    }
+
     /// Assumes that the path provided is a valid file.
-    pub fn run_file_and_display_error(&self, file_path: &Path) {
-        let lua_chunk = self.lua.load(fs::read(file_path).unwrap());
-        let result = lua_chunk
+    /// Run std.lua. The functions it defines are real globals, and write their globals like the ones of config.lua.
+    fn run_std(&self, std_file: &Path) {
+        let previous = self.set_globals_target(self.lua.globals());
+        self.run_file_in_scope(std_file, self.std_scope.clone());
+        self.set_globals_target(previous);
+    }
+
+    /// Make the functions of std.lua and config.lua write globals to `target`, and return the previous target.
+    fn set_globals_target(&self, target: Table) -> Table {
+        self.set_globals_target.call(target).unwrap()
+    }
+
+    /// Run `f` with the functions of std.lua and config.lua writing globals to `config_values`, like config.lua.
+    /// Hooks use it, so that their globals are kept from one page to the next.
+    pub fn with_config_globals<R>(&self, f: impl FnOnce() -> R) -> R {
+        let previous = self.set_globals_target(self.config_values.clone());
+        let result = f();
+        self.set_globals_target(previous);
+        result
+    }
+
+    /// Run a `.lua` file of the input directory, which writes pages with `emit_page`,
+    /// with the globals of the page being compiled.
+    pub fn run_generator(&self, file_path: &Path) {
+        self.run_file_in_scope(file_path, self.page_scope.clone());
+    }
+
+    /// Run a file using `scope` as its globals.
+    /// Assumes that the path provided is a valid file.
+    fn run_file_in_scope(&self, file_path: &Path, scope: Table) {
+        let result = self
+            .lua
+            .load(fs::read(file_path).unwrap())
             .set_name("@".to_owned() + file_path.to_str().unwrap())
+            .set_environment(scope)
             .exec();
         if result.is_err() {
             let error = result.err().unwrap();
@@ -343,6 +1153,15 @@ impl LuaEnvironment {
     }
 }
 
+/// Create an empty table whose missing keys are looked up inside `parent`.
+pub fn create_scope(lua: &Lua, parent: Table) -> Table {
+    let scope = lua.create_table().unwrap();
+    let metatable = lua.create_table().unwrap();
+    metatable.set("__index", parent).unwrap();
+    scope.set_metatable(Some(metatable));
+    scope
+}
+
 fn stringify(value: LuaValue) -> String {
     match value {
         LuaValue::Nil => "nil".to_string(),
@@ -376,9 +1195,10 @@ fn stringify(value: LuaValue) -> String {
             )
         }
         LuaValue::Thread(thread) => format!("Thread({})", thread.to_pointer().addr()),
-        LuaValue::UserData(any_user_data) => {
-            format!("UserData({})", any_user_data.to_pointer().addr())
-        }
+        LuaValue::UserData(any_user_data) => match any_user_data.borrow::<Raw>() {
+            Ok(raw) => raw.0.clone(),
+            Err(_) => format!("UserData({})", any_user_data.to_pointer().addr()),
+        },
         LuaValue::Buffer(buffer) => format!("Buffer(len={})", buffer.len()),
         LuaValue::Error(error) => format!("Error({error})"),
         LuaValue::Other(_) => "Other(???)".to_string(),
@@ -0,0 +1,118 @@
+/// Pagination: a page listing a collection with `paginate(name, size, query)` is written once
+/// for every `size` pages of the collection: `blog/index.html`, `blog/page/2/index.html`, ...
+use mlua::{Lua, Table, Value as LuaValue};
+
+use super::emitted_pages::site_url;
+
+/// Which page of its list the page being compiled is.
+#[derive(Clone, Debug, Default)]
+pub struct Pagination {
+    /// URL of the first page, like `./blog/index.html`.
+    pub url: String,
+    /// Number of the page being compiled, starting at 1.
+    pub current: usize,
+    /// Number of pages of the list, once the page called `paginate`.
+    pub page_count: Option<usize>,
+}
+
+impl Pagination {
+    pub fn new(url: &str) -> Pagination {
+        Pagination {
+            url: url.to_string(),
+            current: 1,
+            page_count: None,
+        }
+    }
+
+    /// The `paginator` table of the current page, listing its part of `items`.
+    /// Remembers how many pages are needed to list every item.
+    pub fn paginator(
+        &mut self,
+        lua: &Lua,
+        items: Vec<LuaValue>,
+        size: usize,
+    ) -> mlua::Result<Table> {
+        if size == 0 {
+            return Err(mlua::Error::runtime(
+                "The page size of paginate should be at least 1",
+            ));
+        }
+        let page_count = items.len().div_ceil(size).max(1);
+        self.page_count = Some(page_count);
+        let current = self.current.min(page_count);
+
+        let paginator = lua.create_table()?;
+        paginator.set("total_items", items.len())?;
+        paginator.set(
+            "items",
+            lua.create_sequence_from(items.into_iter().skip((current - 1) * size).take(size))?,
+        )?;
+        paginator.set("current", current)?;
+        paginator.set("total", page_count)?;
+        paginator.set("size", size)?;
+        paginator.set("first_url", site_url(&page_url(&self.url, 1)))?;
+        paginator.set("last_url", site_url(&page_url(&self.url, page_count)))?;
+        if current > 1 {
+            paginator.set("prev_url", site_url(&page_url(&self.url, current - 1)))?;
+        }
+        if current < page_count {
+            paginator.set("next_url", site_url(&page_url(&self.url, current + 1)))?;
+        }
+        Ok(paginator)
+    }
+}
+
+/// URL of the page `number` of the list starting at `first_page_url`.
+/// `blog/index.html` and `blog.html` both continue with `blog/page/2/index.html`.
+pub fn page_url(first_page_url: &str, number: usize) -> String {
+    if number <= 1 {
+        return first_page_url.to_string();
+    }
+    let directory = match first_page_url.strip_suffix("index.html") {
+        Some(directory) => directory.trim_end_matches('/'),
+        None => first_page_url
+            .rsplit_once('.')
+            .map_or(first_page_url, |(path, _)| path),
+    };
+    if directory.is_empty() || directory == "." {
+        format!("./page/{number}/index.html")
+    } else {
+        format!("{directory}/page/{number}/index.html")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_page_urls() {
+        assert_eq!(page_url("./blog/index.html", 1), "./blog/index.html");
+        assert_eq!(page_url("./blog/index.html", 2), "./blog/page/2/index.html");
+        assert_eq!(page_url("./archive.html", 3), "./archive/page/3/index.html");
+        assert_eq!(page_url("./index.html", 2), "./page/2/index.html");
+    }
+
+    #[test]
+    fn it_splits_items_into_pages() {
+        let lua = Lua::new();
+        let items: Vec<LuaValue> = (1..=5).map(LuaValue::Integer).collect();
+        let mut pagination = Pagination::new("./blog/index.html");
+        pagination.current = 3;
+        let paginator = pagination.paginator(&lua, items, 2).unwrap();
+        assert_eq!(pagination.page_count, Some(3));
+        assert_eq!(paginator.get::<Vec<i64>>("items").unwrap(), vec![5]);
+        assert_eq!(paginator.get::<usize>("total").unwrap(), 3);
+        assert_eq!(
+            paginator.get::<String>("prev_url").unwrap(),
+            "/blog/page/2/"
+        );
+        assert_eq!(paginator.get::<String>("first_url").unwrap(), "/blog/");
+        assert!(
+            paginator
+                .get::<Option<String>>("next_url")
+                .unwrap()
+                .is_none()
+        );
+    }
+}
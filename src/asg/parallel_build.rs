@@ -0,0 +1,239 @@
+/// Compile the pages of a website on several threads at the same time.
+/// A Lua state cannot be shared between threads, so every worker has its own
+/// `LuaEnvironment`, running std.lua and config.lua like the main one.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{
+        Barrier, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use super::build_cache::BuildCache;
+use super::build_options::BuildOptions;
+use super::build_report::BuildReport;
+use super::dependencies::DependencyGraph;
+use super::lua_environment::{FileInfo, LuaEnvironment};
+use super::tokenizer::ParsingCache;
+use super::{prepare_file, render_page};
+
+/// Files to generate. Each worker takes the next one when it is done with the previous one.
+struct Queue<'a> {
+    files: &'a [PathBuf],
+    next: AtomicUsize,
+}
+
+impl<'a> Queue<'a> {
+    fn new(files: &'a [PathBuf]) -> Queue<'a> {
+        Queue {
+            files,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn pop(&self) -> Option<&'a PathBuf> {
+        self.files.get(self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Caches given to a worker when it starts, and given back with what it learned.
+struct WorkerState {
+    parsing_cache: ParsingCache,
+    dependencies: DependencyGraph,
+    build_cache: BuildCache,
+}
+
+struct WorkerResult {
+    state: WorkerState,
+    report: BuildReport,
+    /// Files generated by the worker.
+    generated: Vec<PathBuf>,
+}
+
+/// Everything the workers share during a build.
+struct SharedWork<'a> {
+    options: BuildOptions,
+    input_directory: &'a Path,
+    output_directory: &'a Path,
+    files: Queue<'a>,
+    /// Metadata of the pages, collected before any page is rendered.
+    file_infos: Mutex<Vec<(PathBuf, FileInfo)>>,
+    pages_prepared: Barrier,
+}
+
+/// Waits for the other workers when dropped, even if the worker panicked,
+/// so that the other workers are not stuck waiting for it.
+struct WaitOnDrop<'a>(&'a Barrier);
+
+impl Drop for WaitOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.wait();
+    }
+}
+
+/// Generate `files` using `jobs` workers.
+/// Like a serial build, every page is rendered knowing the metadata of every page.
+/// Values a page stores in Lua, like in the `site` table, are only seen by the pages of its worker.
+/// A worker renders the pages it prepared, as their state is inside its Lua state.
+/// What the workers learned is merged into `env` in a deterministic order, so that
+/// the cache, dependencies and report do not depend on which worker compiled which page.
+pub fn generate_files(
+    env: &mut LuaEnvironment,
+    files: &[PathBuf],
+    input_directory: &Path,
+    output_directory: &Path,
+    jobs: usize,
+) {
+    let work = SharedWork {
+        // The cache is handled by the main environment.
+        options: BuildOptions {
+            cache_directory: None,
+            jobs: 1,
+            ..env.options.clone()
+        },
+        input_directory,
+        output_directory,
+        files: Queue::new(files),
+        file_infos: Mutex::new(vec![]),
+        pages_prepared: Barrier::new(jobs),
+    };
+
+    let results: Vec<WorkerResult> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                let state = WorkerState {
+                    parsing_cache: env.cache.borrow().clone(),
+                    dependencies: env.dependencies.borrow().clone(),
+                    build_cache: env.build_cache.clone(),
+                };
+                let work = &work;
+                scope.spawn(move || run_worker(state, work))
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("A build worker panicked"))
+            .collect()
+    });
+
+    let mut report = BuildReport::new();
+    let reports: Vec<&BuildReport> = results.iter().map(|result| &result.report).collect();
+    for output_file in shared_outputs(&reports) {
+        report.add_error(
+            &output_file.to_string_lossy(),
+            "Written by pages compiled by different workers, the content depends on which one finished last",
+        );
+    }
+
+    for result in results {
+        report.merge(result.report);
+        env.cache
+            .borrow_mut()
+            .merge_pages(&result.state.parsing_cache, &result.generated);
+        env.dependencies
+            .borrow_mut()
+            .merge_pages(&result.state.dependencies, &result.generated);
+        env.build_cache
+            .merge_pages(&result.state.build_cache, &result.generated);
+    }
+
+    report.errors.sort_by(|a, b| a.file.cmp(&b.file));
+    report.warnings.sort_by(|a, b| a.file.cmp(&b.file));
+    report.pages_written.sort();
+    report.files_copied.sort();
+    report.page_timings.sort_by(|a, b| a.0.cmp(&b.0));
+    report.files_unchanged.sort();
+    env.report.borrow_mut().merge(report);
+}
+
+/// Files written by more than one worker. Their content depends on which worker was the last
+/// to write them, so unlike a serial build, the last page does not always win.
+fn shared_outputs(reports: &[&BuildReport]) -> Vec<PathBuf> {
+    let mut writers: BTreeMap<&PathBuf, usize> = BTreeMap::new();
+    for report in reports {
+        let written: BTreeSet<&PathBuf> = report.pages_written.iter().collect();
+        for output_file in written {
+            *writers.entry(output_file).or_default() += 1;
+        }
+    }
+    writers
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(output_file, _)| output_file.clone())
+        .collect()
+}
+
+fn run_worker(state: WorkerState, work: &SharedWork) -> WorkerResult {
+    let pages_prepared = WaitOnDrop(&work.pages_prepared);
+
+    let mut env = LuaEnvironment::new(work.input_directory, work.output_directory, &work.options);
+    *env.cache.borrow_mut() = state.parsing_cache;
+    *env.dependencies.borrow_mut() = state.dependencies;
+    env.build_cache = state.build_cache;
+    env.load_config(work.input_directory);
+    // Problems with config.lua are already reported by the main environment.
+    env.take_report();
+
+    let mut generated = vec![];
+    let mut pages = vec![];
+    while let Some(file) = work.files.pop() {
+        if let Some(page) =
+            prepare_file(&mut env, file, work.input_directory, work.output_directory)
+        {
+            pages.push(page);
+        }
+        if let Some(file_info) = env.cache.borrow().file_cache.get(file) {
+            let mut file_infos = work.file_infos.lock().unwrap();
+            file_infos.push((file.clone(), file_info.clone()));
+        }
+        generated.push(file.clone());
+    }
+
+    drop(pages_prepared);
+    {
+        let file_infos = work.file_infos.lock().unwrap();
+        let mut cache = env.cache.borrow_mut();
+        for (file, file_info) in file_infos.iter() {
+            cache.file_cache.insert(file.clone(), file_info.clone());
+        }
+    }
+
+    for page in pages {
+        render_page(&mut env, page, work.input_directory);
+    }
+
+    WorkerResult {
+        report: env.take_report(),
+        generated,
+        state: WorkerState {
+            parsing_cache: env.cache.take(),
+            dependencies: env.dependencies.take(),
+            build_cache: env.build_cache,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_finds_files_written_by_several_workers() {
+        let mut first = BuildReport::new();
+        first.add_page(Path::new("out/a.html"), Duration::ZERO);
+        first.add_page(Path::new("out/tags.txt"), Duration::ZERO);
+        first.add_page(Path::new("out/tags.txt"), Duration::ZERO);
+        let mut second = BuildReport::new();
+        second.add_page(Path::new("out/b.html"), Duration::ZERO);
+        second.add_page(Path::new("out/tags.txt"), Duration::ZERO);
+
+        assert_eq!(
+            shared_outputs(&[&first, &second]),
+            vec![PathBuf::from("out/tags.txt")]
+        );
+        assert!(shared_outputs(&[&first]).is_empty());
+    }
+}
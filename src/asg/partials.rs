@@ -0,0 +1,104 @@
+/// Partials: templates rendered inside a page with `include("partials/card.html", { title = ... })`.
+/// A partial goes through the same Lua and markdown pipeline as a page, with its own globals:
+/// the variables given to `include`, then the globals of the page including it.
+use std::{
+    cell::RefCell,
+    path::{self, Path, PathBuf},
+};
+
+use mlua::{Lua, Table, Value as LuaValue};
+
+use super::dependencies::{Dependency, DependencyGraph};
+use super::lua_environment::create_scope;
+use super::tokenizer::{self, ParsingCache, StartedTemplate};
+
+/// Files inside the `partials` folder of the input directory are only included, they are not pages.
+pub const PARTIALS_DIRECTORY: &str = "partials";
+
+#[derive(Default)]
+pub struct Partials {
+    /// Globals of the page being compiled, seen by its partials.
+    pub page_scope: Option<Table>,
+    /// Partials being rendered, the innermost last, to stop partials including themselves.
+    pub recursion_path: Vec<PathBuf>,
+}
+
+/// Everything `include` needs, shared with the Lua environment.
+pub struct Includer<'a> {
+    pub partials: &'a RefCell<Partials>,
+    pub cache: &'a RefCell<ParsingCache>,
+    pub dependencies: &'a RefCell<DependencyGraph>,
+    pub input_directory: &'a Path,
+    pub assets_path: &'a Path,
+    /// Whether the values of the partial are escaped, see `escaping`.
+    pub autoescape: bool,
+}
+
+impl Includer<'_> {
+    /// Start rendering `partial`, resolved like a layout, with `vars` as its local variables.
+    /// Lua runs the code of the partial, then gives its result to `finish_include`.
+    pub fn start_include(
+        &self,
+        lua: &Lua,
+        partial: &str,
+        vars: Option<Table>,
+    ) -> mlua::Result<StartedTemplate> {
+        let Some(partial_file) =
+            tokenizer::resolve_path(Path::new(partial), self.input_directory, self.assets_path)
+        else {
+            // Creating the missing partial inside the input directory fixes the page.
+            self.dependencies
+                .borrow_mut()
+                .record(Dependency::File(self.input_directory.join(partial)));
+            return Err(mlua::Error::runtime(format!("Partial {partial} not found")));
+        };
+        if let Ok(absolute_file) = path::absolute(&partial_file) {
+            self.dependencies
+                .borrow_mut()
+                .record(Dependency::File(absolute_file));
+        }
+        let stack =
+            tokenizer::inclusion_loop(&self.partials.borrow().recursion_path, &partial_file);
+        if let Some(stack) = stack {
+            return Err(mlua::Error::runtime(format!(
+                "Infinite inclusion loop in partials: {stack}"
+            )));
+        }
+
+        let parent = self
+            .partials
+            .borrow()
+            .page_scope
+            .clone()
+            .unwrap_or_else(|| lua.globals());
+        let scope = create_scope(lua, parent);
+        if let Some(vars) = vars {
+            for pair in vars.pairs::<LuaValue, LuaValue>() {
+                let (key, value) = pair?;
+                scope.raw_set(key, value)?;
+            }
+        }
+
+        let started = tokenizer::start_template(
+            lua,
+            self.cache,
+            &partial_file,
+            self.input_directory,
+            self.assets_path,
+            &scope,
+            self.autoescape,
+        )?;
+        self.partials.borrow_mut().recursion_path.push(partial_file);
+        Ok(started)
+    }
+}
+
+/// The text of a partial started by `Includer::start_include`, whose code returned `result`.
+pub fn finish_include(
+    partials: &RefCell<Partials>,
+    started: StartedTemplate,
+    result: mlua::Result<usize>,
+) -> mlua::Result<String> {
+    partials.borrow_mut().recursion_path.pop();
+    started.finish(result)
+}
@@ -0,0 +1,59 @@
+/// Files written by `asg new` to create a new website.
+use std::{fs, io, path::Path};
+
+const CONFIG_LUA: &str = r#"-- config.lua is executed once before the pages are built. You can put libraries here!
+
+title = "My website"
+theme = "light"
+
+setvar("profiler", "false")
+setvar("coloredErrors", "true")
+setvar("debugInfo", "false")
+"#;
+
+const INDEX_MD: &str = r#"{%
+setvar("layout", theme .. ".html")
+%}
+
+## Welcome to {{ title }}!
+
+This website was generated with ASG {{ asg_version }}.
+
+## Latest posts
+
+{% for post in posts() do %}
+<a href="{{ post.url }}">
+<div class='card'>
+	<h3 class='title'>{{ post.title }}</h3>
+	<p>{{ post.description }}</p>
+</div>
+</a>
+{% end %}
+"#;
+
+const FIRST_POST_MD: &str = r#"{%
+setvar("layout", theme .. ".html")
+%}
+
+## My first post
+
+Edit `posts/first_post.md` and run `asg serve` to see your changes live.
+"#;
+
+/// Create the files of a minimal blog inside `directory`.
+/// Fails if `directory` already exists and is not empty.
+pub fn create_site(directory: &Path) -> io::Result<()> {
+    if directory.exists() && fs::read_dir(directory)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", directory.display()),
+        ));
+    }
+
+    fs::create_dir_all(directory.join("posts"))?;
+    fs::create_dir_all(directory.join("data"))?;
+    fs::write(directory.join("config.lua"), CONFIG_LUA)?;
+    fs::write(directory.join("index.md"), INDEX_MD)?;
+    fs::write(directory.join("posts").join("first_post.md"), FIRST_POST_MD)?;
+    Ok(())
+}
@@ -0,0 +1,369 @@
+/// Taxonomies: pages grouped by the terms of one of their keys, like their `tags` or a `categories` front matter key.
+/// `taxonomy(name, query)` lists the terms and their pages. Declared in config.lua with `taxonomy_pages(name, options)`,
+/// a taxonomy also writes a page per term and an index page listing the terms.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use mlua::{Lua, Table};
+use serde_json::Value;
+
+use super::collections::{self, Collection, Query};
+use super::data_pages::{self, Row};
+use super::dependencies::Dependency;
+use super::emitted_pages::{self, EmittedContent, EmittedPage};
+use super::lua_environment::{FileInfo, LuaEnvironment};
+use super::tokenizer;
+
+/// Pages written for the terms of a taxonomy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaxonomyPages {
+    pub name: String,
+    /// Template compiled for every term, with the `term` global.
+    pub template: String,
+    /// Template of the page listing the terms, with the `terms` global.
+    pub index_template: Option<String>,
+    /// URL of the page of a term, like `/tags/{slug}/`, see `data_pages::expand_url`.
+    pub url: String,
+    pub index_url: String,
+}
+
+impl TaxonomyPages {
+    /// The pages of the taxonomy `name`, with the `options` given to `taxonomy_pages` in config.lua.
+    pub fn from_lua(name: &str, options: &Table) -> mlua::Result<TaxonomyPages> {
+        let Some(template) = options.get::<Option<String>>("template")? else {
+            return Err(mlua::Error::runtime(format!(
+                "taxonomy_pages(\"{name}\") needs a template"
+            )));
+        };
+        Ok(TaxonomyPages {
+            name: name.to_string(),
+            template,
+            index_template: options.get("index_template")?,
+            url: options
+                .get::<Option<String>>("url")?
+                .unwrap_or(format!("/{name}/{{slug}}/")),
+            index_url: options
+                .get::<Option<String>>("index_url")?
+                .unwrap_or(format!("/{name}/")),
+        })
+    }
+
+    /// The pages of a taxonomy are written by config.lua, which declares them.
+    /// This path identifies them in the cache and in error messages, like `config.lua#tags`.
+    pub fn owner(&self, input_directory: &Path) -> PathBuf {
+        input_directory.join(format!("config.lua#{}", self.name))
+    }
+
+    /// URL of the page of `term`, or of the index page without a term, like `./tags/rust/index.html`.
+    pub fn page_url(&self, term: Option<&Term>) -> Result<String, String> {
+        let url = match term {
+            Some(term) => {
+                let mut row = Row::new();
+                row.insert("name".to_string(), Value::String(term.name.clone()));
+                row.insert("slug".to_string(), Value::String(term.slug.clone()));
+                data_pages::expand_url(&self.url, &row)
+                    .map_err(|e| format!("Term {}: {e}", term.name))?
+            }
+            None => self.index_url.clone(),
+        };
+        emitted_pages::normalize_url(&url)
+    }
+}
+
+/// A term of a taxonomy and the pages using it.
+pub struct Term {
+    pub name: String,
+    /// Unique among the terms of the taxonomy, see `slugs`.
+    pub slug: String,
+    pub pages: Vec<FileInfo>,
+}
+
+/// Term as it appears in URLs: `Rust & Lua` is `rust-lua`.
+pub fn slugify(term: &str) -> String {
+    let mut slug = String::new();
+    for c in term.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Terms written with other cases are the same term: `Rust` and `rust` share their page.
+fn term_key(term: &str) -> String {
+    term.to_lowercase()
+}
+
+/// Slug of a term used by no page, or before its number is added when it shares it with other terms.
+/// A term without letters or digits, like `++`, is `term`.
+fn base_slug(key: &str) -> String {
+    let slug = slugify(key);
+    if slug.is_empty() {
+        "term".to_string()
+    } else {
+        slug
+    }
+}
+
+/// Slug of every term used by `pages`, by `term_key`. Terms sharing a slug, like `C++`, `C#` and `c`, are numbered:
+/// a term written like its slug keeps it, and the others get the first free number in the order of their names,
+/// so `c` is `c`, `C#` is `c-2` and `C++` is `c-3`.
+fn slugs(name: &str, pages: &[FileInfo]) -> HashMap<String, String> {
+    let keys: BTreeSet<String> = pages
+        .iter()
+        .flat_map(|page| terms_of(page, name))
+        .map(|term| term_key(&term))
+        .collect();
+    let mut slugs = HashMap::new();
+    let mut used = HashSet::new();
+    for key in &keys {
+        let slug = slugify(key);
+        if slug == *key {
+            used.insert(slug.clone());
+            slugs.insert(key.clone(), slug);
+        }
+    }
+    for key in &keys {
+        if slugs.contains_key(key) {
+            continue;
+        }
+        let base = base_slug(key);
+        let mut slug = base.clone();
+        let mut number = 2;
+        while used.contains(&slug) {
+            slug = format!("{base}-{number}");
+            number += 1;
+        }
+        used.insert(slug.clone());
+        slugs.insert(key.clone(), slug);
+    }
+    slugs
+}
+
+/// Terms of the taxonomy `name` of a page: its `tags`, or the front matter key `name`,
+/// which can be a list or text separated with commas.
+pub fn terms_of(file_info: &FileInfo, name: &str) -> Vec<String> {
+    let terms = if name == "tags" {
+        file_info.tags.clone()
+    } else {
+        match file_info.extra.get(name) {
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(|value| match value {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+                .collect(),
+            Some(Value::String(s)) => s.split(',').map(ToString::to_string).collect(),
+            Some(Value::Null) | None => vec![],
+            Some(other) => vec![other.to_string()],
+        }
+    };
+    terms
+        .into_iter()
+        .map(|term| term.trim().to_string())
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+/// Query used when a page lists the terms of a taxonomy: the newest pages first.
+pub fn query_from_lua(query: Option<&Table>) -> mlua::Result<Query> {
+    let defaults = Collection {
+        sort: "date".to_string(),
+        reverse: true,
+        ..Collection::from_lua("", None)?
+    };
+    Query::from_lua(&defaults, query)
+}
+
+/// Published pages of `file_cache` listed by `query`.
+/// Data files are not pages, even when they were compiled as the template of other pages.
+fn published_pages(
+    input_directory: &Path,
+    file_cache: &HashMap<PathBuf, FileInfo>,
+    query: &Query,
+) -> Vec<FileInfo> {
+    let data_directory = input_directory.join("data");
+    collections::list(input_directory, file_cache, query)
+        .into_iter()
+        .filter(|file_info| !Path::new(&file_info.filename).starts_with(&data_directory))
+        .collect()
+}
+
+/// Slugs of the terms of the taxonomy `name` used by every published page, whatever the `tag` of a query,
+/// so that the URL of a term is the same on every page.
+fn all_slugs(
+    name: &str,
+    input_directory: &Path,
+    file_cache: &HashMap<PathBuf, FileInfo>,
+) -> HashMap<String, String> {
+    let query = Query {
+        sort: "filename".to_string(),
+        reverse: false,
+        tag: None,
+        limit: None,
+        filter: None,
+    };
+    slugs(name, &published_pages(input_directory, file_cache, &query))
+}
+
+/// Slug of `term` in the taxonomy `name`, even if no page uses it.
+pub fn slug(
+    name: &str,
+    term: &str,
+    input_directory: &Path,
+    file_cache: &HashMap<PathBuf, FileInfo>,
+) -> String {
+    let key = term_key(term.trim());
+    all_slugs(name, input_directory, file_cache)
+        .remove(&key)
+        .unwrap_or_else(|| base_slug(&key))
+}
+
+/// Terms of the taxonomy `name` used by the published pages of `file_cache`, sorted by name.
+/// Terms written with other cases are merged under the first of their names, like `Rust` for `rust`.
+pub fn terms(
+    name: &str,
+    input_directory: &Path,
+    file_cache: &HashMap<PathBuf, FileInfo>,
+    query: &Query,
+) -> Vec<Term> {
+    let mut slugs = all_slugs(name, input_directory, file_cache);
+    let mut terms: BTreeMap<String, (String, Vec<FileInfo>)> = BTreeMap::new();
+    for file_info in published_pages(input_directory, file_cache, query) {
+        for term in terms_of(&file_info, name) {
+            let (term_name, pages) = terms
+                .entry(term_key(&term))
+                .or_insert_with(|| (term.clone(), vec![]));
+            if term < *term_name {
+                *term_name = term;
+            }
+            if !pages.iter().any(|page| page.filename == file_info.filename) {
+                pages.push(file_info.clone());
+            }
+        }
+    }
+    terms
+        .into_iter()
+        .map(|(key, (name, pages))| Term {
+            slug: slugs.remove(&key).unwrap_or_else(|| base_slug(&key)),
+            name,
+            pages,
+        })
+        .collect()
+}
+
+/// Terms as Lua tables: `{ name, slug, url, count, pages }`. `url` is only set when the taxonomy has pages,
+/// and starts from the site root like `/tags/rust/`.
+/// Pages rejected by the `filter` of the query are not counted, and `limit` only shortens `pages`.
+pub fn to_lua(
+    lua: &Lua,
+    terms: Vec<Term>,
+    taxonomy_pages: Option<&TaxonomyPages>,
+    query: &Query,
+) -> mlua::Result<Vec<Table>> {
+    let mut tables = vec![];
+    for mut term in terms {
+        let pages = std::mem::take(&mut term.pages);
+        let mut pages = collections::to_lua(lua, pages, query.filter.as_ref(), None)?;
+        if pages.is_empty() {
+            continue;
+        }
+        let table = lua.create_table()?;
+        table.set("count", pages.len())?;
+        if let Some(limit) = query.limit {
+            pages.truncate(limit);
+        }
+        table.set("pages", lua.create_sequence_from(pages)?)?;
+        if let Some(taxonomy_pages) = taxonomy_pages {
+            let url = taxonomy_pages
+                .page_url(Some(&term))
+                .map_err(mlua::Error::runtime)?;
+            table.set("url", emitted_pages::site_url(&url))?;
+        }
+        table.set("slug", term.slug)?;
+        table.set("name", term.name)?;
+        tables.push(table);
+    }
+    Ok(tables)
+}
+
+/// Emit the page of every term of `taxonomy_pages`, and its index page.
+pub fn emit(
+    env: &LuaEnvironment,
+    taxonomy_pages: &TaxonomyPages,
+    base_input_directory: &Path,
+) -> Result<(), String> {
+    // Any page can use a term.
+    env.dependencies.borrow_mut().record(Dependency::Taxonomy(
+        base_input_directory.to_path_buf(),
+        taxonomy_pages.name.clone(),
+    ));
+    let templates = [
+        Some(&taxonomy_pages.template),
+        taxonomy_pages.index_template.as_ref(),
+    ];
+    for template in templates.into_iter().flatten() {
+        if tokenizer::resolve_path(Path::new(template), base_input_directory, &env.assets_path)
+            .is_none()
+        {
+            return Err(format!("Template {template} not found"));
+        }
+    }
+
+    let query = query_from_lua(None).map_err(|e| e.to_string())?;
+    let terms = terms(
+        &taxonomy_pages.name,
+        base_input_directory,
+        &env.cache.borrow().file_cache,
+        &query,
+    );
+    let terms = to_lua(&env.lua, terms, Some(taxonomy_pages), &query).map_err(|e| e.to_string())?;
+
+    let mut emitted_pages = vec![];
+    for term in &terms {
+        let url = taxonomy_pages.page_url(Some(&Term {
+            name: term.get("name").map_err(|e| e.to_string())?,
+            slug: term.get("slug").map_err(|e| e.to_string())?,
+            pages: vec![],
+        }))?;
+        let vars = env.lua.create_table().map_err(|e| e.to_string())?;
+        vars.set("term", term).map_err(|e| e.to_string())?;
+        emitted_pages.push(EmittedPage {
+            url,
+            content: EmittedContent::Template(PathBuf::from(&taxonomy_pages.template)),
+            vars: Some(vars),
+        });
+    }
+    if let Some(index_template) = &taxonomy_pages.index_template {
+        let vars = env.lua.create_table().map_err(|e| e.to_string())?;
+        let terms = env
+            .lua
+            .create_sequence_from(terms)
+            .map_err(|e| e.to_string())?;
+        vars.set("terms", terms).map_err(|e| e.to_string())?;
+        emitted_pages.push(EmittedPage {
+            url: taxonomy_pages.page_url(None)?,
+            content: EmittedContent::Template(PathBuf::from(index_template)),
+            vars: Some(vars),
+        });
+    }
+    env.emitted_pages.borrow_mut().extend(emitted_pages);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_slugifies_terms() {
+        assert_eq!(slugify("Rust"), "rust");
+        assert_eq!(slugify(" Rust & Lua! "), "rust-lua");
+        assert_eq!(slugify("Économie"), "économie");
+    }
+}
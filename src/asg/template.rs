@@ -0,0 +1,619 @@
+/// Template parser: turns the tags of a template into a tree of nodes, with nested
+/// `for`, `while` and `if` statements (with `elseif` and `else`) and named blocks,
+/// then compiles the tree into the source of one Luau function.
+///
+/// The function is called with an output table, a marker, an errors table and the function escaping
+/// values, and returns the number of values it put in the output table: text, escaped values of
+/// `{{ }}` tags, and the marker followed by the name and mode of a block (or `false` at the end
+/// of the block). The errors of `{{ }}` tags are put in the errors table.
+///
+/// A source map gives the position in the template of each line of the function, so that
+/// Lua errors can point at the template.
+use serde::{Deserialize, Serialize};
+
+use super::blocks;
+use super::tokenizer::tokenize;
+
+/// Line and column in a file, counting from 1. Columns count characters.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Positions in the template of the lines of the generated code.
+/// Lines added by the compiler, like the start of the function, have no position.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SourceMap {
+    lines: Vec<Option<Position>>,
+}
+
+impl SourceMap {
+    /// Position in the template of `line` of the generated code, counting from 1.
+    pub fn position(&self, line: usize) -> Option<Position> {
+        self.lines.get(line.checked_sub(1)?).copied().flatten()
+    }
+}
+
+/// The source of the Luau function rendering a template.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Compiled {
+    pub code: String,
+    pub source_map: SourceMap,
+}
+
+/// A tag of a template, as found by the tokenizer.
+#[derive(Debug, PartialEq)]
+enum Tag<'a> {
+    Text(&'a str),
+    /// `{{ expression }}`
+    Value(&'a str),
+    /// `{% code %}`, and whether it starts with a trim marker: `{%- code %}`.
+    Controller(&'a str, bool),
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    Value(String, Position),
+    Code(String, Position),
+    /// A `for`, `while` or `if` statement: its opening tag then its `elseif` and `else` tags.
+    Statement(Vec<Branch>),
+    /// `{% block name mode %}`, with the name and the mode.
+    Block {
+        header: String,
+        body: Vec<Node>,
+    },
+}
+
+/// A tag of a statement, with its content.
+#[derive(Debug, PartialEq)]
+struct Branch {
+    tag: String,
+    position: Position,
+    body: Vec<Node>,
+}
+
+/// Opening tag whose nodes are being collected, waiting for its closing tag.
+enum Open {
+    /// The tags of the statement so far, with the content of the ones before the last.
+    Statement(Vec<Branch>),
+    Block(String, Position),
+}
+
+impl Open {
+    fn describe(&self) -> String {
+        match self {
+            Open::Statement(branches) => format!("{{% {} %}}", branches[0].tag),
+            Open::Block(header, _) => format!("{{% block {header} %}}"),
+        }
+    }
+
+    fn position(&self) -> Position {
+        match self {
+            Open::Statement(branches) => branches[0].position,
+            Open::Block(_, position) => *position,
+        }
+    }
+}
+
+/// Turns offsets in a template into positions.
+struct Lines<'a> {
+    template: &'a str,
+    starts: Vec<usize>,
+    first_line: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn new(template: &'a str, first_line: usize) -> Lines<'a> {
+        let newlines = template.match_indices('\n').map(|(i, _)| i + 1);
+        Lines {
+            template,
+            starts: std::iter::once(0).chain(newlines).collect(),
+            first_line,
+        }
+    }
+
+    /// Position of `token`, which must be a part of the template.
+    fn position(&self, token: &str) -> Position {
+        let offset = token.as_ptr() as usize - self.template.as_ptr() as usize;
+        let index = self.starts.partition_point(|&start| start <= offset) - 1;
+        Position {
+            line: self.first_line + index,
+            column: self.template[self.starts[index]..offset].chars().count() + 1,
+        }
+    }
+}
+
+/// Tags of `template`. Comments are dropped, and the text next to a trim marker loses its whitespace
+/// on the side of the tag.
+fn tags<'a>(template: &'a str, lines: &Lines) -> Result<Vec<Tag<'a>>, (String, Position)> {
+    let mut tags = vec![];
+    // Opening delimiter of the `{{ }}` or `{% %}` tag being read, and where its content starts.
+    // Inside a tag, only its closing delimiter counts: `{% local t = {#items} %}` is code.
+    let mut open: Option<(&str, Option<usize>)> = None;
+    let mut comment: Option<&str> = None;
+    let mut trim_next = false;
+    for t in tokenize(template) {
+        let trim = std::mem::take(&mut trim_next);
+        // Escaped delimiters like `\%}` are text, and their backslash is just before them.
+        let offset = t.as_ptr() as usize - template.as_ptr() as usize;
+        let delimiter = if template[..offset].ends_with('\\') {
+            ""
+        } else {
+            t
+        };
+        if comment.is_some() {
+            if delimiter == "#}" || delimiter == "-#}" {
+                comment = None;
+                trim_next = delimiter == "-#}";
+            }
+            continue;
+        }
+        if let Some((opening, content_start)) = &mut open {
+            let is_value = opening.starts_with("{{");
+            let closing = if is_value {
+                ["}}", "-}}"]
+            } else {
+                ["%}", "-%}"]
+            };
+            if closing.contains(&delimiter) {
+                trim_next = delimiter.starts_with('-');
+                open = None;
+                continue;
+            }
+            let start = *content_start.get_or_insert(offset);
+            let content = &template[start..offset + t.len()];
+            let tag = if is_value {
+                Tag::Value(content)
+            } else {
+                Tag::Controller(content, *opening == "{%-")
+            };
+            // The content read so far grows with the token.
+            if start < offset {
+                *tags.last_mut().unwrap() = tag;
+            } else {
+                tags.push(tag);
+            }
+            continue;
+        }
+        // Without a `#}` after it, `{#` is text, like `## Title {#id}` in markdown.
+        let delimiter = if (delimiter == "{#" || delimiter == "{#-")
+            && !template[offset + t.len()..].contains("#}")
+        {
+            ""
+        } else {
+            delimiter
+        };
+        if (delimiter == "{{-" || delimiter == "{%-" || delimiter == "{#-")
+            && let Some(Tag::Text(text)) = tags.last_mut()
+        {
+            *text = text.trim_end();
+        }
+        match delimiter {
+            "{{" | "{{-" | "{%" | "{%-" => open = Some((t, None)),
+            "{#" | "{#-" => comment = Some(t),
+            // Closing delimiters outside of a tag are ignored.
+            "}}" | "-}}" | "%}" | "-%}" | "#}" | "-#}" => {}
+            _ if trim => tags.push(Tag::Text(t.trim_start())),
+            _ => tags.push(Tag::Text(t)),
+        }
+    }
+    if let Some(opening) = comment {
+        return Err((
+            format!("{opening} is missing its #}}, write \\{opening} to show it"),
+            lines.position(opening),
+        ));
+    }
+    Ok(tags)
+}
+
+/// Whether `code` opens a statement closed by `{% end %}`, like `for post in posts() do`.
+/// Complete statements like `for i = 1, 3 do f(i) end` are code.
+fn is_opening(code: &str) -> bool {
+    let is_statement = ["for ", "if ", "while "]
+        .iter()
+        .any(|keyword| code.starts_with(keyword));
+    is_statement && (code.ends_with(" do") || code.ends_with(" then"))
+}
+
+/// Nodes of `template`, whose first line is `first_line` of its file.
+/// Errors come with the position of the tag responsible.
+fn parse(template: &str, first_line: usize) -> Result<Vec<Node>, (String, Position)> {
+    let lines = Lines::new(template, first_line);
+    let mut stack: Vec<(Open, Vec<Node>)> = vec![];
+    let mut nodes: Vec<Node> = vec![];
+    // Like in Lua long strings, the newline right after the tag of a branch is dropped,
+    // unless the tag controls its whitespace with trim markers.
+    let mut is_after_branch = false;
+    for tag in tags(template, &lines)? {
+        let drops_newline = std::mem::take(&mut is_after_branch);
+        let (code, is_trimmed) = match tag {
+            Tag::Text(text) if drops_newline => {
+                let text = text
+                    .strip_prefix("\r\n")
+                    .or(text.strip_prefix('\n'))
+                    .unwrap_or(text);
+                nodes.push(Node::Text(text.to_string()));
+                continue;
+            }
+            Tag::Text(text) => {
+                nodes.push(Node::Text(text.to_string()));
+                continue;
+            }
+            Tag::Value(expression) => {
+                nodes.push(Node::Value(
+                    expression.to_string(),
+                    lines.position(expression),
+                ));
+                continue;
+            }
+            Tag::Controller(code, is_trimmed) => (code, is_trimmed),
+        };
+        let stripped = code.trim();
+        let position = lines.position(stripped);
+        let error = |message: String| Err((message, position));
+        if is_opening(stripped) {
+            let branch = Branch {
+                tag: stripped.to_string(),
+                position,
+                body: vec![],
+            };
+            stack.push((Open::Statement(vec![branch]), std::mem::take(&mut nodes)));
+            is_after_branch = !is_trimmed;
+        } else if stripped == "else" || stripped.starts_with("elseif ") {
+            let Some((Open::Statement(branches), _)) = stack.last_mut() else {
+                return error(format!("{{% {stripped} %}} is not inside an {{% if %}}"));
+            };
+            let is_if = branches[0].tag.starts_with("if ");
+            let previous = branches.last_mut().unwrap();
+            if !is_if || previous.tag == "else" {
+                return error(format!(
+                    "{{% {stripped} %}} cannot follow {{% {} %}}",
+                    previous.tag
+                ));
+            }
+            previous.body = std::mem::take(&mut nodes);
+            branches.push(Branch {
+                tag: stripped.to_string(),
+                position,
+                body: vec![],
+            });
+            is_after_branch = !is_trimmed;
+        } else if stripped == "end" {
+            match stack.pop() {
+                Some((Open::Statement(mut branches), parent_nodes)) => {
+                    branches.last_mut().unwrap().body = std::mem::replace(&mut nodes, parent_nodes);
+                    nodes.push(Node::Statement(branches));
+                }
+                Some((open @ Open::Block(..), _)) => {
+                    return error(format!(
+                        "{{% end %}} cannot close {}, use {{% endblock %}}",
+                        open.describe()
+                    ));
+                }
+                None => return error("{% end %} does not close anything".to_string()),
+            }
+        } else if stripped == "endblock" {
+            match stack.pop() {
+                Some((Open::Block(header, _), parent_nodes)) => {
+                    let body = std::mem::replace(&mut nodes, parent_nodes);
+                    nodes.push(Node::Block { header, body });
+                }
+                Some((open, _)) => {
+                    return error(format!(
+                        "{{% endblock %}} cannot close {}, use {{% end %}}",
+                        open.describe()
+                    ));
+                }
+                None => return error("{% endblock %} does not close anything".to_string()),
+            }
+        } else if let Some(header) = stripped.strip_prefix("block ")
+            && blocks::parse_header(header).is_ok()
+        {
+            if let Some((open, _)) = stack
+                .iter()
+                .find(|(open, _)| matches!(open, Open::Block(..)))
+            {
+                return error(format!(
+                    "Blocks cannot be nested, {} is not closed",
+                    open.describe()
+                ));
+            }
+            stack.push((
+                Open::Block(header.trim().to_string(), position),
+                std::mem::take(&mut nodes),
+            ));
+        } else {
+            nodes.push(Node::Code(code.to_string(), lines.position(code)));
+        }
+    }
+    if let Some((open, _)) = stack.last() {
+        let closing = match open {
+            Open::Statement(_) => "{% end %}",
+            Open::Block(..) => "{% endblock %}",
+        };
+        return Err((
+            format!("{} is missing its {closing}", open.describe()),
+            open.position(),
+        ));
+    }
+    Ok(nodes)
+}
+
+/// Lua string literal containing `s`.
+fn lua_string(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' => literal.push_str("\\\\"),
+            '"' => literal.push_str("\\\""),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push('\t'),
+            c if c.is_ascii_control() => literal.push_str(&format!("\\{:03}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Writes the generated code and its source map.
+#[derive(Default)]
+struct Generator {
+    compiled: Compiled,
+    /// Position of the line being written.
+    position: Option<Position>,
+}
+
+impl Generator {
+    fn push(&mut self, code: &str) {
+        for line in code.split_inclusive('\n') {
+            self.compiled.code.push_str(line);
+            if line.ends_with('\n') {
+                self.compiled.source_map.lines.push(self.position);
+            }
+        }
+    }
+
+    /// Write code of the template, which starts at `position`.
+    fn push_template_code(&mut self, code: &str, position: Position) {
+        self.position = Some(position);
+        for (i, line) in code.split_inclusive('\n').enumerate() {
+            if i > 0 {
+                self.position = Some(Position {
+                    line: position.line + i,
+                    column: 1,
+                });
+            }
+            self.push(line);
+        }
+    }
+
+    fn generate(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            match node {
+                Node::Text(text) => {
+                    self.push(&format!("__n += 1 __out[__n] = {}\n", lua_string(text)));
+                }
+                // The newline ends comments at the end of the expression.
+                Node::Value(expression, position) => {
+                    self.position = Some(*position);
+                    self.push("__n += 1 __out[__n] = __value(function() return (");
+                    self.push_template_code(expression, *position);
+                    self.push("\n) end)\n");
+                }
+                Node::Code(code, position) if is_catchable(code) => {
+                    self.position = Some(*position);
+                    self.push("__code(function() ");
+                    self.push_template_code(code, *position);
+                    self.push("\nend)\n");
+                }
+                Node::Code(code, position) => {
+                    self.push_template_code(code, *position);
+                    self.push("\n");
+                }
+                Node::Statement(branches) => {
+                    for branch in branches {
+                        self.push_template_code(&branch.tag, branch.position);
+                        self.push("\n");
+                        self.generate(&branch.body);
+                    }
+                    self.push("end\n");
+                }
+                Node::Block { header, body } => {
+                    self.push(&format!(
+                        "__n += 1 __out[__n] = __block __n += 1 __out[__n] = {}\n",
+                        lua_string(header)
+                    ));
+                    self.generate(body);
+                    self.push("__n += 1 __out[__n] = __block __n += 1 __out[__n] = false\n");
+                }
+            }
+        }
+    }
+}
+
+/// Whether `code` can run in a function of its own: it does not declare locals used by the
+/// rest of the template, nor `return` or leave the loop around it.
+fn is_catchable(code: &str) -> bool {
+    code.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .all(|word| !matches!(word, "local" | "return" | "break" | "continue" | "goto"))
+        && !code.contains("...")
+}
+
+/// Start of the function rendering a template. A value or a `{% %}` failing is recorded in the
+/// errors table and renders nothing, so that the rest of the template is still rendered.
+const VALUE_HEADER: &str = "local __out, __block, __errors, __escape = ...
+local __n = 0
+local function __value(f)
+local ok, value = pcall(f)
+if ok then return __escape(value) end
+__errors[#__errors + 1] = value
+return \"\"
+end
+local function __code(f)
+local ok, err = pcall(f)
+if not ok then __errors[#__errors + 1] = err end
+end
+";
+
+/// The function rendering `template`, whose first line is `first_line` of its file.
+/// Errors come with the position of the tag responsible.
+pub fn compile(template: &str, first_line: usize) -> Result<Compiled, (String, Position)> {
+    let nodes = parse(template, first_line)?;
+    let mut generator = Generator::default();
+    generator.push(VALUE_HEADER);
+    generator.generate(&nodes);
+    generator.position = None;
+    generator.push("return __n\n");
+    Ok(generator.compiled)
+}
+
+/// The function rendering a file which is not a template, like an SVG image.
+pub fn compile_text(text: &str) -> Compiled {
+    let mut generator = Generator::default();
+    generator.push(&format!(
+        "local __out = ...\n__out[1] = {}\nreturn 1\n",
+        lua_string(text)
+    ));
+    generator.compiled
+}
+
+/// The function running a `.lua` file used as a template. It renders nothing.
+pub fn compile_lua(lua_code: &str) -> Compiled {
+    let mut generator = Generator::default();
+    generator.push("do\n");
+    generator.push_template_code(lua_code, Position { line: 1, column: 1 });
+    generator.position = None;
+    generator.push("\nend\nreturn 0\n");
+    generator.compiled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(line: usize, column: usize) -> Position {
+        Position { line, column }
+    }
+
+    #[test]
+    fn it_parses_nested_statements() {
+        let nodes = parse(
+            "{% for i = 1, 2 do %}{% if i == 1 then %}a{% elseif i == 2 then %}b{% else %}c{% end %}{% end %}",
+            1,
+        )
+        .unwrap();
+        let branch = |tag: &str, column, body| Branch {
+            tag: tag.to_string(),
+            position: position(1, column),
+            body,
+        };
+        assert_eq!(
+            nodes,
+            vec![Node::Statement(vec![branch(
+                "for i = 1, 2 do",
+                4,
+                vec![Node::Statement(vec![
+                    branch("if i == 1 then", 25, vec![Node::Text("a".to_string())]),
+                    branch("elseif i == 2 then", 46, vec![Node::Text("b".to_string())]),
+                    branch("else", 71, vec![Node::Text("c".to_string())]),
+                ])],
+            )])]
+        );
+        assert_eq!(
+            parse("a\n{{ b }}\n{% end %}", 3).unwrap_err(),
+            (
+                "{% end %} does not close anything".to_string(),
+                position(5, 4)
+            )
+        );
+        assert!(parse("{% for i = 1, 2 do %}", 1).is_err());
+        assert!(parse("{% else %}", 1).is_err());
+        assert!(parse("{% while true do %}{% else %}{% end %}", 1).is_err());
+        assert!(parse("{% block a %}{% end %}", 1).is_err());
+        assert!(parse("{% block a %}{% block b %}{% endblock %}{% endblock %}", 1).is_err());
+    }
+
+    #[test]
+    fn it_maps_generated_lines_to_the_template() {
+        let compiled = compile("a\n{{ b }}\n{%\nx = 1\ny() %}", 1).unwrap();
+        let line_of = |needle: &str| {
+            compiled
+                .code
+                .lines()
+                .position(|line| line.contains(needle))
+                .unwrap()
+                + 1
+        };
+        let map = &compiled.source_map;
+        assert_eq!(map.position(1), None);
+        assert_eq!(map.position(line_of("return ( b")), Some(position(2, 3)));
+        assert_eq!(map.position(line_of("x = 1")), Some(position(4, 1)));
+        assert_eq!(map.position(line_of("y()")), Some(position(5, 1)));
+    }
+
+    #[test]
+    fn it_trims_whitespace_and_drops_comments() {
+        let nodes = parse(
+            "<ul>\n  {%- x = 1 -%}\n  <li>{{- y -}} </li>{# {{ z }} #}\n",
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Text("<ul>".to_string()),
+                Node::Code(" x = 1 ".to_string(), position(2, 6)),
+                Node::Text("<li>".to_string()),
+                Node::Value(" y ".to_string(), position(3, 10)),
+                Node::Text("</li>".to_string()),
+                Node::Text("\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_keeps_unclosed_comments_as_text() {
+        let nodes = parse("## Title {#my-id}\n{{ x }} {#- a", 1).unwrap();
+        let text: String = nodes
+            .iter()
+            .map(|node| match node {
+                Node::Text(text) => text.clone(),
+                Node::Value(value, _) => format!("[{value}]"),
+                _ => String::new(),
+            })
+            .collect();
+        assert_eq!(text, "## Title {#my-id}\n[ x ] {#- a");
+    }
+
+    #[test]
+    fn it_reads_delimiters_inside_tags_as_code() {
+        let nodes = parse("{% t = {#items} %}{{ ({#t})[1] }}{% u = {a={}} %}", 1).unwrap();
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Code(" t = {#items} ".to_string(), position(1, 3)),
+                Node::Value(" ({#t})[1] ".to_string(), position(1, 21)),
+                Node::Code(" u = {a={}} ".to_string(), position(1, 36)),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_catches_code_which_keeps_no_locals() {
+        assert!(is_catchable(" x = f(1) "));
+        assert!(is_catchable(" locals.x = 1 "));
+        assert!(!is_catchable(" local x = 1 "));
+        assert!(!is_catchable(" if x then break end "));
+        assert!(!is_catchable(" print(...) "));
+    }
+
+    #[test]
+    fn it_escapes_lua_strings() {
+        assert_eq!(lua_string("a\"b\\c\nd\u{1}2"), "\"a\\\"b\\\\c\\nd\\0012\"");
+    }
+}
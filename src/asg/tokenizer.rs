@@ -1,19 +1,49 @@
 use core::str;
 use std::{
-    cmp::min,
+    cell::RefCell,
     collections::HashMap,
-    path::{Path, PathBuf},
+    path::{self, Path, PathBuf},
     time::{self, SystemTime},
 };
 
 use crate::asg::{
-    date_format::DATE_FORMAT,
-    lua_environment::{FileInfo, LuaEnvironment, get_asset_dir_path, get_exe_dir_path},
+    date_format::{DATE_FORMAT, parse_date},
+    dependencies::Dependency,
+    lua_environment::{FILE_INFO_FIELDS, FileInfo, LuaEnvironment, get_exe_dir_path},
 };
 use chrono::DateTime;
 use colored::Colorize;
+use mlua::{Function, Lua, LuaSerdeExt, Table, Thread, ThreadStatus};
+use serde::{Deserialize, Serialize};
 
-use super::{git_times, handle_html::strip_html};
+use super::{
+    blocks::{self, BlockMode},
+    build_cache::hash_file,
+    escaping,
+    front_matter::{FRONT_MATTER_FORMATS, FrontMatter, split_front_matter, to_config_value},
+    git_times,
+    handle_html::strip_html,
+    template::{self, Compiled, Position},
+};
+
+/// Delimiters of tags, the longest first. `-` trims the whitespace on its side of the tag,
+/// and `{# #}` is a comment.
+pub const DELIMITERS: &[&str] = &[
+    "{{-", "{%-", "{#-", "-}}", "-%}", "-#}", "{{", "}}", "{%", "%}", "{#", "#}",
+];
+
+/// Length of the delimiter at the start of `data`.
+fn delimiter_length(data: &[u8]) -> Option<usize> {
+    DELIMITERS
+        .iter()
+        .find(|delimiter| data.starts_with(delimiter.as_bytes()))
+        .map(|delimiter| delimiter.len())
+}
+
+/// Whether `data` starts with an escaped delimiter, like `\{{`.
+fn is_escaped_delimiter(data: &[u8]) -> bool {
+    data.first() == Some(&b'\\') && delimiter_length(&data[1..]).is_some()
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Tokenized<'a> {
@@ -42,31 +72,22 @@ impl<'a> Iterator for Tokenized<'a> {
         let data_slice = self.underlying_data.as_bytes();
 
         let mut i = start;
-        let p = &data_slice[i..min(i + 3, len)];
-        if p == b"\\{{" || p == b"\\}}" || p == b"\\{%" || p == b"\\%}" {
+        if is_escaped_delimiter(&data_slice[i..]) {
             // We have an escaped delimiter, we remove one backslash and we keep going.
             start += 1;
-            i += 2;
-        } else {
+            i += 1 + delimiter_length(&data_slice[i + 1..]).unwrap_or(0);
+        } else if let Some(length) = delimiter_length(&data_slice[i..]) {
             // If we start with a delimiter, we should return it
-            let p = &data_slice[i..min(i + 2, len)];
-            if p == b"{{" || p == b"}}" || p == b"{%" || p == b"%}" {
-                self.position = i + 2;
-                return Some(&self.underlying_data[start..min(i + 2, len)]);
-            }
+            self.position = i + length;
+            return Some(&self.underlying_data[start..i + length]);
+        } else {
+            i += 1;
         }
 
-        i += 1;
-
         while i < len {
-            // Does not matter if p is not a valid unicode string.
-            let p = &data_slice[i..min(i + 3, len)];
-            if p == b"\\{{" || p == b"\\}}" || p == b"\\{%" || p == b"\\%}" {
-                self.position = i;
-                return Some(&self.underlying_data[start..i]);
-            }
-            let p = &data_slice[i..min(i + 2, len)];
-            if p == b"{{" || p == b"}}" || p == b"{%" || p == b"%}" {
+            // Does not matter if the data is not a valid unicode string, delimiters are ASCII.
+            let rest = &data_slice[i..];
+            if is_escaped_delimiter(rest) || delimiter_length(rest).is_some() {
                 self.position = i;
                 return Some(&self.underlying_data[start..i]);
             }
@@ -79,32 +100,50 @@ impl<'a> Iterator for Tokenized<'a> {
 
 // -------------------------
 
-#[derive(Clone, Copy, Debug)]
-pub enum ParseChunkType {
-    RawText,
-    LuaController, // {% lua %}
-    LuaValue,      // {{ value }}
-}
-
-#[derive(Clone, Debug)]
-pub struct ParseChunk {
-    chunk: String,
-    chunk_type: ParseChunkType,
-}
-
 /// Contains a partially parsed file.
-/// The lua chunks needs to be executed and converted from markdown to HTML.
-#[derive(Clone)]
+/// The lua code needs to be executed and converted from markdown to HTML.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PartialParse {
-    chunks: Vec<ParseChunk>,
-    timestamp: time::SystemTime,
+    /// The Luau function rendering the file, see `template::compile`.
+    pub compiled: Compiled,
+    /// Bytecode of `compiled`, so that layouts shared by many pages are compiled by Luau once per build.
+    /// It is not saved with the cache, which it would make twice as large.
+    /// None when the code does not compile: the code is loaded to report the error.
+    #[serde(skip)]
+    bytecode: Option<Vec<u8>>,
+    /// Why the template could not be parsed, like an `{% end %}` closing nothing, and where.
+    template_error: Option<(String, Position)>,
+    /// Hash of the content that was parsed. The parse is reused while the content is the same.
+    content_hash: String,
     real_path: PathBuf,
+    /// Metadata at the top of the file, exposed to Lua as the `page` table.
+    front_matter: Option<FrontMatter>,
     _partial_parse_time: time::Duration,
 }
 
+impl PartialParse {
+    pub fn front_matter(&self) -> Option<&FrontMatter> {
+        self.front_matter.as_ref()
+    }
+}
+
+/// Dates of the first and last commits of a file, formatted with `DATE_FORMAT`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GitTimes {
+    /// Hash of the content of the file when `git log` was run.
+    content_hash: String,
+    pub created_at: String,
+    pub last_modified: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ParsingCache {
     pub cache: HashMap<PathBuf, PartialParse>,
     pub file_cache: HashMap<PathBuf, FileInfo>,
+    pub git_times: HashMap<PathBuf, GitTimes>,
+    /// URLs of the pages emitted by each page with `emit_page`.
+    #[serde(default)]
+    pub emitted_pages: HashMap<PathBuf, Vec<String>>,
 }
 
 impl ParsingCache {
@@ -112,8 +151,61 @@ impl ParsingCache {
         ParsingCache {
             cache: HashMap::new(),
             file_cache: HashMap::new(),
+            git_times: HashMap::new(),
+            emitted_pages: HashMap::new(),
+        }
+    }
+
+    /// Forget the files that were deleted, for example since the cache was saved.
+    pub fn retain_existing(&mut self) {
+        self.cache.retain(|path, _| path.exists());
+        self.file_cache
+            .retain(|_, file_info| Path::new(&file_info.filename).exists());
+        self.git_times.retain(|path, _| path.exists());
+    }
+
+    /// Take the parsed files and dates of `other`, and the metadata of `pages`, which it compiled.
+    pub fn merge_pages(&mut self, other: &ParsingCache, pages: &[PathBuf]) {
+        for (path, partial_parse) in &other.cache {
+            self.cache.insert(path.clone(), partial_parse.clone());
+        }
+        for (path, times) in &other.git_times {
+            self.git_times.insert(path.clone(), times.clone());
+        }
+        for page in pages {
+            match other.file_cache.get(page) {
+                Some(file_info) => self.file_cache.insert(page.clone(), file_info.clone()),
+                None => self.file_cache.remove(page),
+            };
+            match other.emitted_pages.get(page) {
+                Some(urls) => self.emitted_pages.insert(page.clone(), urls.clone()),
+                None => self.emitted_pages.remove(page),
+            };
         }
     }
+
+    /// Dates of the first and last commits of `path`, or its modification time when it is not in git.
+    /// Running `git log` is slow, so the result is kept until the content of the file changes.
+    pub fn git_times(&mut self, path: &Path) -> GitTimes {
+        let content_hash = hash_file(path).unwrap_or_default();
+        if let Some(times) = self.git_times.get(path)
+            && times.content_hash == content_hash
+        {
+            return times.clone();
+        }
+        let blame = git_times::git_blame(path);
+        let times = GitTimes {
+            content_hash,
+            created_at: git_times::get_git_creation_time(&blame, path)
+                .format(DATE_FORMAT)
+                .to_string(),
+            last_modified: git_times::get_git_modification_time(&blame, path)
+                .format(DATE_FORMAT)
+                .to_string(),
+        };
+        self.git_times.insert(path.to_path_buf(), times.clone());
+        times
+    }
 }
 
 impl Default for ParsingCache {
@@ -125,28 +217,12 @@ impl Default for ParsingCache {
 // For small lists, vec is faster than hashset
 pub const LUA_TEMPLATE_FORMATS: &[&str] = &[".html", ".md", ".css", ".js", ".txt", ".asg", ".xml"];
 
-/// Pure function (but reads IO).
-/// Takes the content of a file and outputs a partially parsed version.
-/// Does not execute lua, nor markdown and is memoized.
-pub fn tokenize_file(
-    cache: &mut ParsingCache,
-    relative_path: &Path,
-    input_dir: &Path,
-) -> Option<PartialParse> {
-    if cache.cache.contains_key(relative_path) {
-        let last_parse = &cache.cache[relative_path];
-        let last_modified_time = last_parse.real_path.metadata().unwrap().modified().unwrap();
-        if last_modified_time < last_parse.timestamp {
-            return Some(last_parse.clone());
-        }
-    }
-
-    let start_of_parse = time::Instant::now();
-    // To resolve the relative path, we try in order:
-    // - relative to cwd.
-    // - relative to the executable
-    // - relative to executable/assets
-
+/// Find the file that `relative_path` refers to. We try in order:
+/// - relative to cwd.
+/// - relative to the input directory
+/// - relative to the executable
+/// - relative to the assets directory (executable/assets by default)
+pub fn resolve_path(relative_path: &Path, input_dir: &Path, assets_dir: &Path) -> Option<PathBuf> {
     let mut input_path = relative_path.to_path_buf();
     if !input_path.exists() || !input_path.is_file() {
         input_path = input_dir.join(relative_path);
@@ -155,16 +231,45 @@ pub fn tokenize_file(
         input_path = get_exe_dir_path().join(relative_path);
     }
     if !input_path.exists() || !input_path.is_file() {
-        input_path = get_asset_dir_path().join(relative_path);
+        input_path = assets_dir.join(relative_path);
     }
 
     if !input_path.exists() || !input_path.is_file() {
         return None;
     }
+    Some(input_path)
+}
+
+fn to_bytecode(compiled: &Compiled) -> Option<Vec<u8>> {
+    mlua::Compiler::new().compile(&compiled.code).ok()
+}
+
+/// Pure function (but reads IO).
+/// Takes the content of a file and outputs a partially parsed version.
+/// Does not execute lua, nor markdown and is memoized.
+pub fn tokenize_file(
+    cache: &mut ParsingCache,
+    relative_path: &Path,
+    input_dir: &Path,
+    assets_dir: &Path,
+) -> Option<PartialParse> {
+    let input_path = resolve_path(relative_path, input_dir, assets_dir)?;
 
     let Ok(file_content) = std::fs::read_to_string(&input_path) else {
         return None;
     };
+    let content_hash = blake3::hash(file_content.as_bytes()).to_hex().to_string();
+
+    if let Some(last_parse) = cache.cache.get_mut(&input_path)
+        && last_parse.content_hash == content_hash
+    {
+        if last_parse.bytecode.is_none() {
+            last_parse.bytecode = to_bytecode(&last_parse.compiled);
+        }
+        return Some(last_parse.clone());
+    }
+
+    let start_of_parse = time::Instant::now();
 
     let mut templating = false;
 
@@ -177,94 +282,42 @@ pub fn tokenize_file(
 
     let is_pure_lua = input_path.to_str().unwrap().ends_with(".lua");
     if !templating || is_pure_lua {
-        let chunk_type = if is_pure_lua {
-            ParseChunkType::LuaController
+        let compiled = if is_pure_lua {
+            template::compile_lua(&file_content)
         } else {
-            ParseChunkType::RawText
+            template::compile_text(&file_content)
         };
-        let chunks: Vec<ParseChunk> = vec![ParseChunk {
-            chunk: file_content,
-            chunk_type,
-        }];
         return Some(PartialParse {
-            chunks,
-            timestamp: time::SystemTime::now(),
-            real_path: relative_path.to_path_buf(),
+            bytecode: to_bytecode(&compiled),
+            compiled,
+            template_error: None,
+            content_hash,
+            real_path: input_path,
+            front_matter: None,
             _partial_parse_time: start_of_parse.elapsed(),
         });
     }
 
-    let mut chunks: Vec<ParseChunk> = Vec::new();
-    let mut is_in_lua = false;
-    let mut is_in_lua_controller = false;
-    let mut is_in_loop = false;
-    let mut is_end_of_loop = false;
-    let mut lua_code_buffer = String::new();
-
-    for t in tokenize(&file_content) {
-        if t == "{{" {
-            is_in_lua = true;
-        } else if t == "}}" {
-            is_in_lua = false;
-        } else if t == "{%" {
-            is_in_lua_controller = true;
-        } else if t == "%}" {
-            is_in_lua_controller = false;
-            if is_in_loop {
-                if is_end_of_loop {
-                    is_in_loop = false;
-                    is_end_of_loop = false;
-                    lua_code_buffer.push_str("return table.concat(result)");
-
-                    chunks.push(ParseChunk {
-                        chunk: lua_code_buffer,
-                        chunk_type: ParseChunkType::LuaValue,
-                    });
-                    lua_code_buffer = String::new();
-                } else {
-                    is_end_of_loop = true;
-                }
-            }
-        } else if is_in_loop {
-            if is_in_lua {
-                lua_code_buffer.push_str(&format!("table.insert(result,({t}))\n"));
-            } else if is_in_lua_controller {
-                lua_code_buffer.push_str(&format!("{}\n", t.trim()));
-            } else {
-                lua_code_buffer.push_str(&format!("table.insert(result,[=====[{t}]=====])\n"));
-            }
-        } else if is_in_lua {
-            chunks.push(ParseChunk {
-                chunk: format!("return {t}"),
-                chunk_type: ParseChunkType::LuaValue,
-            });
-        } else if is_in_lua_controller {
-            let stripped = t.trim();
-            if stripped.starts_with("for ")
-                || stripped.starts_with("if ")
-                || stripped.starts_with("while ")
-            {
-                is_in_loop = true;
-                is_end_of_loop = false;
-                lua_code_buffer = format!("result = {{}}\n{stripped}\n").to_string();
-            } else {
-                chunks.push(ParseChunk {
-                    chunk: t.to_string(),
-                    chunk_type: ParseChunkType::LuaController,
-                });
-            }
-        } else {
-            chunks.push(ParseChunk {
-                chunk: t.to_string(),
-                chunk_type: ParseChunkType::RawText,
-            });
-        }
-    }
+    let has_front_matter = FRONT_MATTER_FORMATS
+        .iter()
+        .any(|format| input_path.to_str().unwrap().ends_with(format));
+    let (front_matter, body, front_matter_lines) = if has_front_matter {
+        split_front_matter(&file_content)
+    } else {
+        (None, file_content.as_str(), 0)
+    };
+    let (compiled, template_error) = match template::compile(body, front_matter_lines + 1) {
+        Ok(compiled) => (compiled, None),
+        Err(e) => (template::compile_text(""), Some(e)),
+    };
 
     let result = Some(PartialParse {
-        chunks,
-        timestamp: time::SystemTime::now(),
+        bytecode: to_bytecode(&compiled),
+        compiled,
+        template_error,
+        content_hash,
         real_path: input_path.clone(),
+        front_matter,
         _partial_parse_time: start_of_parse.elapsed(),
     });
     // Don't fill the cache with large files (>10Mo)
@@ -283,26 +336,176 @@ pub fn tokenize_file(
 pub fn compile_file(
     env: &mut LuaEnvironment,
     in_path: &Path,
-    out_path: Option<&Path>,
+    _out_path: Option<&Path>,
+    base_input_dir: &Path,
+) -> Option<String> {
+    compile_file_recursive(env, in_path, base_input_dir, &mut vec![])
+}
+
+/// A page compiled without its layouts.
+pub struct PageBody {
+    content: BodyContent,
+    recursion_path: Vec<PathBuf>,
+}
+
+enum BodyContent {
+    Compiled(CompiledFile),
+    /// The code of the page waits for the metadata of every page to list them.
+    Waiting(Box<StartedFile>, Thread),
+}
+
+/// Compile the content of a page and record its metadata, without applying its layouts.
+/// The code of the page runs in its own thread: when it lists pages, with `posts()` for instance,
+/// it waits until `apply_layouts` resumes it, once the metadata of every page is known.
+/// Meanwhile, the page is listed with the metadata it had when it started waiting.
+/// The layouts can be applied later with `apply_layouts`, after restoring the state of the page.
+pub fn compile_page_body(
+    env: &mut LuaEnvironment,
+    in_path: &Path,
+    base_input_dir: &Path,
+) -> Option<PageBody> {
+    let mut recursion_path = vec![];
+    let started = start_file_content(env, in_path, base_input_dir, &mut recursion_path)?;
+    let code = match &started.code {
+        Ok(code) => code.clone(),
+        Err(e) => {
+            let result = Err(e.clone());
+            let compiled =
+                finish_file_content(env, started, result, base_input_dir, &recursion_path);
+            return Some(PageBody {
+                content: BodyContent::Compiled(compiled),
+                recursion_path,
+            });
+        }
+    };
+    let thread = env.lua.create_thread(code).unwrap();
+    *env.preparing_thread.borrow_mut() = Some(thread.clone());
+    let result = thread.resume::<Option<usize>>(started.output.args());
+    *env.preparing_thread.borrow_mut() = None;
+    let content = match result {
+        Ok(None) if thread.status() == ThreadStatus::Resumable => {
+            let mut html = started.output.text();
+            if is_markdown(&started.partial_parse.real_path) {
+                html = render_markdown(&html).unwrap_or(html);
+            }
+            record_file_info(env, &started, &html, base_input_dir, &recursion_path, false);
+            BodyContent::Waiting(Box::new(started), thread)
+        }
+        result => BodyContent::Compiled(finish_file_content(
+            env,
+            started,
+            result.map(Option::unwrap_or_default),
+            base_input_dir,
+            &recursion_path,
+        )),
+    };
+    Some(PageBody {
+        content,
+        recursion_path,
+    })
+}
+
+/// Wrap the body of a page compiled by `compile_page_body` in its layouts.
+pub fn apply_layouts(
+    env: &mut LuaEnvironment,
+    page_body: PageBody,
     base_input_dir: &Path,
 ) -> Option<String> {
-    let mut v = vec![];
-    compile_file_recursive(env, in_path, out_path, base_input_dir, &mut v)
+    let PageBody {
+        content,
+        mut recursion_path,
+    } = page_body;
+    let compiled = match content {
+        BodyContent::Compiled(compiled) => compiled,
+        BodyContent::Waiting(started, thread) => {
+            let result = thread
+                .resume::<Option<usize>>(())
+                .map(Option::unwrap_or_default);
+            finish_file_content(env, *started, result, base_input_dir, &recursion_path)
+        }
+    };
+    apply_layout(env, compiled, base_input_dir, &mut recursion_path)
+}
+
+/// A file compiled without its layout.
+struct CompiledFile {
+    content: String,
+    layout: Option<String>,
+    real_path: PathBuf,
+    are_errors_colored: bool,
+}
+
+/// A file whose globals are set and whose code is loaded, see `finish_file_content`.
+struct StartedFile {
+    in_path: PathBuf,
+    partial_parse: PartialParse,
+    /// The code rendering the file, or why it could not be loaded.
+    code: mlua::Result<Function>,
+    output: CodeOutput,
 }
 
 fn compile_file_recursive(
     env: &mut LuaEnvironment,
     in_path: &Path,
-    _out_path: Option<&Path>,
     base_input_dir: &Path,
     recursion_path: &mut Vec<PathBuf>,
 ) -> Option<String> {
-    let partial_parse = tokenize_file(&mut env.cache.borrow_mut(), in_path, base_input_dir);
-    partial_parse.as_ref()?; // if none, return
-    let partial_parse = partial_parse.unwrap();
-    recursion_path.push(partial_parse.real_path.as_path().to_owned());
+    let compiled = compile_file_content(env, in_path, base_input_dir, recursion_path)?;
+    apply_layout(env, compiled, base_input_dir, recursion_path)
+}
 
-    let mut raw_data = String::new();
+fn compile_file_content(
+    env: &mut LuaEnvironment,
+    in_path: &Path,
+    base_input_dir: &Path,
+    recursion_path: &mut Vec<PathBuf>,
+) -> Option<CompiledFile> {
+    let started = start_file_content(env, in_path, base_input_dir, recursion_path)?;
+    let result = started
+        .code
+        .clone()
+        .and_then(|code| code.call::<usize>(started.output.args()));
+    Some(finish_file_content(
+        env,
+        started,
+        result,
+        base_input_dir,
+        recursion_path,
+    ))
+}
+
+/// Set the globals of the file `in_path` and load its code, which the caller runs.
+fn start_file_content(
+    env: &mut LuaEnvironment,
+    in_path: &Path,
+    base_input_dir: &Path,
+    recursion_path: &mut Vec<PathBuf>,
+) -> Option<StartedFile> {
+    let partial_parse = tokenize_file(
+        &mut env.cache.borrow_mut(),
+        in_path,
+        base_input_dir,
+        &env.assets_path,
+    );
+    let Some(partial_parse) = partial_parse else {
+        // Creating the missing layout inside the input directory fixes the page.
+        env.dependencies
+            .borrow_mut()
+            .record(Dependency::File(base_input_dir.join(in_path)));
+        if let Some(including_file) = recursion_path.last() {
+            env.report.borrow_mut().add_error(
+                &including_file.to_string_lossy(),
+                &format!("Layout {} not found", in_path.display()),
+            );
+        }
+        return None;
+    };
+    recursion_path.push(partial_parse.real_path.as_path().to_owned());
+    if let Ok(real_path) = path::absolute(&partial_parse.real_path) {
+        env.dependencies
+            .borrow_mut()
+            .record(Dependency::File(real_path));
+    }
 
     let file_path = partial_parse.real_path.as_path();
     let file_metadata = file_path.metadata().unwrap();
@@ -312,101 +515,148 @@ fn compile_file_recursive(
 
     let datetime: DateTime<chrono::Local> =
         file_metadata.modified().unwrap_or(SystemTime::now()).into();
-    let blame = git_times::git_blame(file_path);
+    let git_times = env.cache.borrow_mut().git_times(file_path);
 
     file_info_table
         .set("last_modified_os", datetime.format(DATE_FORMAT).to_string())
         .unwrap();
 
-    file_info_table
-        .set(
-            "last_modified",
-            git_times::get_git_modification_time(&blame)
-                .format(DATE_FORMAT)
-                .to_string(),
-        )
+    // Git dates change with new commits, which the build cache does not see.
+    let dependencies = env.dependencies.clone();
+    let read_git_time = env
+        .lua
+        .create_function(move |_, (_, key): (Table, String)| {
+            dependencies.borrow_mut().record(Dependency::Unwatched);
+            Ok(match key.as_str() {
+                "last_modified" => Some(git_times.last_modified.clone()),
+                "created_at" => Some(git_times.created_at.clone()),
+                _ => None,
+            })
+        })
         .unwrap();
+    let metatable = env.lua.create_table().unwrap();
+    metatable.set("__index", read_git_time).unwrap();
+    file_info_table.set_metatable(Some(metatable));
 
-    file_info_table
-        .set(
-            "created_at",
-            git_times::get_git_creation_time(&blame)
-                .format(DATE_FORMAT)
-                .to_string(),
-        )
-        .unwrap();
+    env.page_scope.set("file", file_info_table).unwrap();
 
-    env.lua.globals().set("file", file_info_table).unwrap();
+    // A template which cannot be parsed is not compiled, rather than written without its content.
+    if let Some((error, position)) = &partial_parse.template_error {
+        env.display_template_error(error, file_path, *position);
+        return None;
+    }
+    let front_matter = partial_parse.front_matter.clone().unwrap_or_default();
+    apply_front_matter(env, &front_matter, recursion_path.len() == 1);
 
-    for chunk in &partial_parse.chunks {
-        match chunk.chunk_type {
-            ParseChunkType::RawText => {
-                raw_data.push_str(&chunk.chunk);
-            }
-            ParseChunkType::LuaController => {
-                let lua_chunk = env.lua.load(&chunk.chunk);
-                let result = lua_chunk
-                    .set_name("@".to_owned() + &file_path.to_string_lossy())
-                    .exec();
-                match result {
-                    Ok(()) => {}
-                    Err(e) => {
-                        let error_msg = e.to_string();
-                        let error_file = file_path.to_string_lossy();
-                        env.display_error(&error_msg, &error_file, Some(&chunk.chunk));
-                    }
-                }
-            }
-            ParseChunkType::LuaValue => {
-                let lua_chunk = env.lua.load(&chunk.chunk);
-                let result: Result<mlua::Value, mlua::Error> = lua_chunk
-                    .set_name("@".to_owned() + file_path.to_str().unwrap())
-                    .call(());
-                match result {
-                    Ok(r) => {
-                        if let Ok(d) = r.to_string() {
-                            raw_data.push_str(&d);
-                        } else if r.is_null() {
-                            raw_data.push_str("nil");
-                        } else {
-                            raw_data.push_str("<LUA VALUE>");
+    let autoescape = env
+        .get_config("autoescape")
+        .is_none_or(|value| value != "false");
+    let output = CodeOutput::new(&env.lua, file_path, autoescape);
+    let code = load_code(&env.lua, &partial_parse, &env.page_scope);
+    Some(StartedFile {
+        in_path: in_path.to_path_buf(),
+        partial_parse,
+        code,
+        output,
+    })
+}
+
+/// Apply the output of the code of `started`, which returned `result`, and record the metadata of the file.
+fn finish_file_content(
+    env: &mut LuaEnvironment,
+    started: StartedFile,
+    result: mlua::Result<usize>,
+    base_input_dir: &Path,
+    recursion_path: &[PathBuf],
+) -> CompiledFile {
+    let file_path = started.partial_parse.real_path.as_path();
+    let segments = started.output.segments(result, |error| {
+        env.display_lua_error(&error, file_path);
+    });
+
+    // A page with a layout fills its blocks, a layout fills the blocks with a mode.
+    // Other blocks are shown where they are declared.
+    let has_layout = env
+        .config_table
+        .borrow()
+        .get("layout")
+        .is_some_and(|layout| !layout.is_empty());
+    let is_page = recursion_path.len() == 1;
+    let mut raw_data = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => raw_data.push_str(&text),
+            Segment::Block {
+                name,
+                mode,
+                mut content,
+            } if has_layout && (is_page || mode.is_some()) => {
+                if is_markdown(file_path) {
+                    match render_markdown(&content) {
+                        Ok(data) => content = data,
+                        Err(error_msg) => {
+                            env.display_error(&error_msg, &file_path.to_string_lossy(), None);
                         }
                     }
-                    Err(e) => {
-                        let error_msg = e.to_string();
-                        let error_file = file_path.to_string_lossy();
-                        env.display_error(&error_msg, &error_file, Some(&chunk.chunk));
-                    }
                 }
+                env.blocks
+                    .fill(&name, mode.unwrap_or(BlockMode::Replace), content);
+            }
+            Segment::Block { name, content, .. } => {
+                raw_data.push_str(&env.blocks.resolve(&name, content));
             }
         }
     }
 
-    if file_path.to_str().unwrap().ends_with(".md") {
-        let mut options = markdown::Options::gfm();
-        options.compile.allow_dangerous_html = true;
-        options.compile.allow_dangerous_protocol = true;
-        options.compile.gfm_tagfilter = false;
-        let result = markdown::to_html_with_options(&raw_data, &options);
-        if let Ok(data) = result {
-            raw_data = data;
-        } else {
-            let error_msg = result.err().unwrap().to_string();
-            let error_file = file_path.to_string_lossy();
-            env.display_error(&error_msg, &error_file, None);
+    if is_markdown(file_path) {
+        match render_markdown(&raw_data) {
+            Ok(data) => raw_data = data,
+            Err(error_msg) => env.display_error(&error_msg, &file_path.to_string_lossy(), None),
         }
     }
 
+    let (layout, are_errors_colored) = record_file_info(
+        env,
+        &started,
+        &raw_data,
+        base_input_dir,
+        recursion_path,
+        true,
+    );
+    CompiledFile {
+        content: raw_data,
+        layout,
+        real_path: file_path.to_path_buf(),
+        are_errors_colored,
+    }
+}
+
+/// Record the metadata of the page or layout of `started`, which rendered `html`.
+/// Returns the layout of the file and whether errors are colored.
+/// Problems are only reported when `is_final`: the metadata of a page waiting to list pages
+/// is recorded again once it is finished.
+fn record_file_info(
+    env: &LuaEnvironment,
+    started: &StartedFile,
+    html: &str,
+    base_input_dir: &Path,
+    recursion_path: &[PathBuf],
+    is_final: bool,
+) -> (Option<String>, bool) {
+    let in_path = started.in_path.as_path();
+    let file_path = started.partial_parse.real_path.as_path();
     let tags: Vec<String>;
     let title;
     let description;
 
     // Strip script and style
 
-    let without_html = strip_html(&raw_data);
+    let without_html = strip_html(html);
     let maybe_layout_file;
     let word_count;
     let are_errors_colored;
+    let mut draft;
+    let mut publish_date;
 
     {
         let config = env.config_table.borrow();
@@ -434,78 +684,412 @@ fn compile_file_recursive(
 
         maybe_layout_file = config.get("layout").map(ToString::to_string);
         are_errors_colored = config.get("coloredErrors").unwrap_or(&String::new()) == "true";
+        draft = config.get("draft").is_some_and(|d| d == "true");
+        publish_date = config.get("publish_date").cloned();
     }
 
-    if let Some(layout_file) = maybe_layout_file
-        && !layout_file.is_empty()
-    {
-        let layout_file = Path::new(&layout_file).to_path_buf();
-        #[allow(clippy::print_stdout)]
-        if recursion_path.contains(&layout_file) {
-            let m_yellow = |s: &str| {
-                if are_errors_colored {
-                    s.yellow()
-                } else {
-                    s.clear()
+    // Pages can also use globals: `draft = true` and `publish_date = "2025-11-02"`.
+    draft = draft || env.page_scope.get::<bool>("draft").unwrap_or(false);
+    if let Ok(Some(date)) = env.page_scope.get::<Option<String>>("publish_date") {
+        publish_date = Some(date);
+    }
+    let publish_date = match publish_date {
+        Some(date) => match parse_date(&date) {
+            Some(date) => date.format(DATE_FORMAT).to_string(),
+            None => {
+                // Layouts see the same value, only report it once.
+                if recursion_path.len() == 1 && is_final {
+                    env.report.borrow_mut().add_warning(
+                        &file_path.to_string_lossy(),
+                        &format!("Invalid publish_date {date}, the page is published"),
+                    );
                 }
-            };
-
-            println!(
-                "{}",
-                m_yellow("Warning: Infinite inclusion loop in layouts")
-            );
-            println!("  The recursion stack is:");
-            println!(
-                "  {}",
-                recursion_path
-                    .iter_mut()
-                    .map(|p| p.to_string_lossy().to_string())
-                    .collect::<Vec<String>>()
-                    .join(",")
-            );
-            println!("The last file is repeated, this is a loop.");
-        } else {
-            env.lua.globals().set("body", raw_data).unwrap();
-            let result = compile_file_recursive(
-                env,
-                &layout_file,
-                _out_path,
-                base_input_dir,
-                recursion_path,
-            );
-            if let Some(result) = result {
-                raw_data = result;
-            } else {
-                return None;
+                String::new()
             }
-        }
-    }
+        },
+        None => String::new(),
+    };
 
-    let datetime: DateTime<chrono::Utc> =
-        file_metadata.modified().unwrap_or(SystemTime::now()).into();
-    let blame = git_times::git_blame(file_path);
+    let datetime: DateTime<chrono::Utc> = file_path
+        .metadata()
+        .and_then(|metadata| metadata.modified())
+        .unwrap_or(SystemTime::now())
+        .into();
+    let git_times = env.cache.borrow_mut().git_times(file_path);
+    let front_matter = started.partial_parse.front_matter.clone();
 
     let fpi = FileInfo {
         filename: file_path.to_str().unwrap().to_string(),
-        url: get_destination_url(file_path, base_input_dir),
+        url: if recursion_path.len() == 1 {
+            env.destination_url(in_path, base_input_dir)
+        } else {
+            get_destination_url(file_path, base_input_dir)
+        },
         size: 0_i64,
         word_count: word_count as i64,
         last_modified_os: datetime.format("%d/%m/%Y %T").to_string(),
-        last_modified: git_times::get_git_modification_time(&blame)
-            .format("%d/%m/%Y %T")
-            .to_string(),
-        created_at: git_times::get_git_creation_time(&blame)
-            .format("%d/%m/%Y %T")
-            .to_string(),
+        last_modified: git_times.last_modified,
+        created_at: git_times.created_at,
         title: title.unwrap_or(String::new()),
         description: description.unwrap_or(String::new()),
         tags: tags.iter().map(ToString::to_string).collect(),
+        draft,
+        publish_date,
+        extra: front_matter
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| !FILE_INFO_FIELDS.contains(&key.as_str()))
+            .collect(),
     };
     env.cache
         .borrow_mut()
         .file_cache
         .insert(in_path.to_path_buf(), fpi);
-    Some(raw_data)
+
+    (maybe_layout_file, are_errors_colored)
+}
+
+/// Part of a rendered file: text, or the content of a `{% block %}`.
+enum Segment {
+    Text(String),
+    Block {
+        name: String,
+        mode: Option<BlockMode>,
+        content: String,
+    },
+}
+
+/// The code of `partial_parse`, compiled by `template::compile`, with `scope` as its globals.
+fn load_code(lua: &Lua, partial_parse: &PartialParse, scope: &Table) -> mlua::Result<Function> {
+    let chunk_name = "@".to_owned() + &partial_parse.real_path.to_string_lossy();
+    let chunk = match &partial_parse.bytecode {
+        Some(bytecode) => lua.load(bytecode),
+        None => lua.load(&partial_parse.compiled.code),
+    };
+    chunk
+        .set_name(&chunk_name)
+        .set_environment(scope.clone())
+        .into_function()
+}
+
+/// The tables given to the code of a file, which it fills while it runs.
+struct CodeOutput {
+    output: Table,
+    block_marker: Table,
+    errors: Table,
+    escape: Function,
+}
+
+impl CodeOutput {
+    fn new(lua: &Lua, file_path: &Path, autoescape: bool) -> CodeOutput {
+        let autoescape = autoescape && escaping::is_escaped(file_path);
+        CodeOutput {
+            output: lua.create_table().unwrap(),
+            block_marker: lua.create_table().unwrap(),
+            errors: lua.create_table().unwrap(),
+            escape: escaping::escaper(lua, autoescape).unwrap(),
+        }
+    }
+
+    /// Arguments of the code of the file.
+    fn args(&self) -> (Table, Table, Table, Function) {
+        (
+            self.output.clone(),
+            self.block_marker.clone(),
+            self.errors.clone(),
+            self.escape.clone(),
+        )
+    }
+
+    /// Number of values written so far. Values can be nil.
+    fn written_count(&self) -> usize {
+        self.output
+            .pairs::<usize, mlua::Value>()
+            .filter_map(Result::ok)
+            .map(|(i, _)| i)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Text written so far by code which is still running, blocks included.
+    fn text(&self) -> String {
+        self.split(self.written_count(), |_| {})
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) | Segment::Block { content: text, .. } => text,
+            })
+            .collect()
+    }
+
+    /// Split the output into segments once the code returned `result`, the number of values it wrote.
+    /// Errors are given to `on_error`: a failing value or `{% %}` renders nothing, a failing
+    /// statement stops the file, and the output produced before the error is kept.
+    fn segments(
+        &self,
+        result: mlua::Result<usize>,
+        mut on_error: impl FnMut(mlua::Error),
+    ) -> Vec<Segment> {
+        for error in self
+            .errors
+            .sequence_values::<mlua::Error>()
+            .filter_map(Result::ok)
+        {
+            on_error(error);
+        }
+        let count = match result {
+            Ok(count) => count,
+            Err(e) => {
+                on_error(e);
+                self.written_count()
+            }
+        };
+        self.split(count, on_error)
+    }
+
+    fn split(&self, count: usize, mut on_error: impl FnMut(mlua::Error)) -> Vec<Segment> {
+        let mut segments = vec![];
+        let mut raw_data = String::new();
+        let mut open_block: Option<(String, Option<BlockMode>)> = None;
+        let mut values = (1..=count).map(|i| {
+            self.output
+                .raw_get::<mlua::Value>(i)
+                .unwrap_or(mlua::Value::Nil)
+        });
+        while let Some(value) = values.next() {
+            let is_marker = value
+                .as_table()
+                .is_some_and(|t| t.to_pointer() == self.block_marker.to_pointer());
+            if !is_marker {
+                if let Ok(d) = value.to_string() {
+                    raw_data.push_str(&d);
+                } else if value.is_null() {
+                    raw_data.push_str("nil");
+                } else {
+                    raw_data.push_str("<LUA VALUE>");
+                }
+                continue;
+            }
+            // The marker is followed by the header of the block, or false at its end.
+            match values
+                .next()
+                .and_then(|header| header.as_string().map(|h| h.to_string_lossy()))
+            {
+                Some(header) => match blocks::parse_header(&header) {
+                    Ok(header) => {
+                        segments.push(Segment::Text(std::mem::take(&mut raw_data)));
+                        open_block = Some(header);
+                    }
+                    Err(error) => on_error(mlua::Error::runtime(error)),
+                },
+                None => {
+                    if let Some((name, mode)) = open_block.take() {
+                        segments.push(Segment::Block {
+                            name,
+                            mode,
+                            content: std::mem::take(&mut raw_data),
+                        });
+                    }
+                }
+            }
+        }
+        // The code failed inside a block.
+        if let Some((name, mode)) = open_block {
+            segments.push(Segment::Block {
+                name,
+                mode,
+                content: std::mem::take(&mut raw_data),
+            });
+        }
+        segments.push(Segment::Text(raw_data));
+        segments
+    }
+}
+
+fn is_markdown(file_path: &Path) -> bool {
+    file_path.to_string_lossy().ends_with(".md")
+}
+
+fn render_markdown(raw_data: &str) -> Result<String, String> {
+    let mut options = markdown::Options::gfm();
+    options.compile.allow_dangerous_html = true;
+    options.compile.allow_dangerous_protocol = true;
+    options.compile.gfm_tagfilter = false;
+    markdown::to_html_with_options(raw_data, &options).map_err(|e| e.to_string())
+}
+
+/// A template started by `start_template`. Lua runs its code, so that a page including it
+/// can wait inside it, see `compile_page_body`.
+pub struct StartedTemplate {
+    real_path: PathBuf,
+    pub code: Function,
+    output: CodeOutput,
+}
+
+impl StartedTemplate {
+    /// Arguments of the code of the template.
+    pub fn args(&self) -> (Table, Table, Table, Function) {
+        self.output.args()
+    }
+
+    /// The text rendered by the code of the template, which returned `result`.
+    /// The first error stops the rendering.
+    pub fn finish(self, result: mlua::Result<usize>) -> mlua::Result<String> {
+        let mut first_error = None;
+        let segments = self.output.segments(result, |error| {
+            first_error.get_or_insert(error);
+        });
+        if let Some(error) = first_error {
+            return Err(error);
+        }
+        // Partials are not part of the layout chain, their blocks are shown with their content.
+        let raw_data: String = segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) | Segment::Block { content: text, .. } => text,
+            })
+            .collect();
+        if is_markdown(&self.real_path) {
+            return render_markdown(&raw_data).map_err(mlua::Error::runtime);
+        }
+        Ok(raw_data)
+    }
+}
+
+/// Start rendering the template `file_path` like the content of a page, with `scope` as its globals.
+/// Its front matter is ignored.
+pub fn start_template(
+    lua: &Lua,
+    cache: &RefCell<ParsingCache>,
+    file_path: &Path,
+    input_dir: &Path,
+    assets_dir: &Path,
+    scope: &Table,
+    autoescape: bool,
+) -> mlua::Result<StartedTemplate> {
+    let partial_parse = tokenize_file(&mut cache.borrow_mut(), file_path, input_dir, assets_dir)
+        .ok_or_else(|| mlua::Error::runtime(format!("Could not read {}", file_path.display())))?;
+    if let Some((error, position)) = partial_parse.template_error {
+        let location = format_location(&partial_parse.real_path, position);
+        return Err(mlua::Error::runtime(format!("{location}: {error}")));
+    }
+    let code = load_code(lua, &partial_parse, scope)?;
+    Ok(StartedTemplate {
+        output: CodeOutput::new(lua, &partial_parse.real_path, autoescape),
+        real_path: partial_parse.real_path,
+        code,
+    })
+}
+
+/// `file:line:column`, the position of an error in a template.
+pub fn format_location(file: &Path, position: Position) -> String {
+    format!(
+        "{}:{}:{}",
+        file.to_string_lossy(),
+        position.line,
+        position.column
+    )
+}
+
+/// When `file` is already being compiled, including it again would never end:
+/// returns the files being compiled, ending with `file` repeated.
+pub fn inclusion_loop(recursion_path: &[PathBuf], file: &Path) -> Option<String> {
+    if !recursion_path.iter().any(|p| p == file) {
+        return None;
+    }
+    let files: Vec<String> = recursion_path
+        .iter()
+        .map(PathBuf::as_path)
+        .chain([file])
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+    Some(files.join(","))
+}
+
+/// Compile the layout chosen by `compiled`, with `body` set to its content.
+fn apply_layout(
+    env: &mut LuaEnvironment,
+    compiled: CompiledFile,
+    base_input_dir: &Path,
+    recursion_path: &mut Vec<PathBuf>,
+) -> Option<String> {
+    let CompiledFile {
+        content: raw_data,
+        layout: maybe_layout_file,
+        real_path: file_path,
+        are_errors_colored,
+    } = compiled;
+    let Some(layout_file) = maybe_layout_file.filter(|layout_file| !layout_file.is_empty()) else {
+        return Some(raw_data);
+    };
+
+    let layout_file = Path::new(&layout_file).to_path_buf();
+    let resolved_layout_file = resolve_path(&layout_file, base_input_dir, &env.assets_path);
+    #[allow(clippy::print_stdout)]
+    if let Some(stack) = resolved_layout_file.and_then(|f| inclusion_loop(recursion_path, &f)) {
+        let m_yellow = |s: &str| {
+            if are_errors_colored {
+                s.yellow()
+            } else {
+                s.clear()
+            }
+        };
+
+        println!(
+            "{}",
+            m_yellow("Warning: Infinite inclusion loop in layouts")
+        );
+        println!("  The recursion stack is:");
+        println!("  {stack}");
+        println!("The last file is repeated, this is a loop.");
+        env.report.borrow_mut().add_warning(
+            &file_path.to_string_lossy(),
+            "Infinite inclusion loop in layouts",
+        );
+        Some(raw_data)
+    } else {
+        env.page_scope.set("body", escaping::Raw(raw_data)).unwrap();
+        // The layout decides which layout it uses.
+        env.config_table.borrow_mut().remove("layout");
+        compile_file_recursive(env, &layout_file, base_input_dir, recursion_path)
+    }
+}
+
+/// Front matter keys that act like a `setvar` call at the top of the page.
+const FRONT_MATTER_VARIABLES: &[&str] = &[
+    "title",
+    "description",
+    "tags",
+    "draft",
+    "publish_date",
+    "layout",
+];
+
+/// Make the front matter of the page available as the `page` table and as `setvar` values,
+/// so that `setvar` calls in the page still take precedence over it.
+/// Layouts are not pages: their front matter can only choose their own layout.
+fn apply_front_matter(env: &LuaEnvironment, front_matter: &FrontMatter, is_page: bool) {
+    {
+        let mut config = env.config_table.borrow_mut();
+        let is_overridden = |key: &str| env.options.config_overrides.iter().any(|(k, _)| k == key);
+        for (key, value) in front_matter {
+            let is_variable = if is_page {
+                FRONT_MATTER_VARIABLES.contains(&key.as_str())
+            } else {
+                key == "layout"
+            };
+            if is_variable && !is_overridden(key) {
+                config.insert(key.clone(), to_config_value(value));
+            }
+        }
+    }
+
+    if is_page {
+        let options = mlua::SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .serialize_unit_to_null(false);
+        let page_table = env.lua.to_value_with(front_matter, options).unwrap();
+        env.page_scope.set("page", page_table).unwrap();
+    }
 }
 
 pub fn get_destination_url(file_path: &Path, base_input_dir: &Path) -> String {
@@ -549,4 +1133,60 @@ mod tests {
             result
         );
     }
+
+    #[test]
+    fn it_renders_from_the_cached_bytecode() {
+        let directory = std::env::temp_dir().join("asg_test_bytecode");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("page.txt"), "{{ 1 + 1 }}").unwrap();
+        std::fs::write(directory.join("broken.txt"), "{% x = = 1 %}").unwrap();
+        let lua = Lua::new();
+        let render = |parse: &PartialParse| {
+            let mut errors = vec![];
+            let output = CodeOutput::new(&lua, &parse.real_path, false);
+            let result = load_code(&lua, parse, &lua.globals())
+                .and_then(|code| code.call::<usize>(output.args()));
+            let segments = output.segments(result, |e| errors.push(e.to_string()));
+            let text = segments
+                .into_iter()
+                .map(|segment| match segment {
+                    Segment::Text(text) => text,
+                    Segment::Block { content, .. } => content,
+                })
+                .collect::<String>();
+            (text, errors)
+        };
+
+        let mut cache = ParsingCache::new();
+        let tokenize = |cache: &mut ParsingCache, name: &str| {
+            tokenize_file(cache, Path::new(name), &directory, &directory).unwrap()
+        };
+        let mut parse = tokenize(&mut cache, "page.txt");
+        // The code is not loaded again.
+        parse.compiled.code = "error('not compiled')".to_string();
+        assert_eq!(render(&parse), ("2".to_string(), vec![]));
+
+        let (text, errors) = render(&tokenize(&mut cache, "broken.txt"));
+        assert_eq!(text, "");
+        assert!(errors[0].contains("broken.txt"), "{errors:?}");
+
+        // The bytecode is compiled again after the cache is loaded.
+        let json = serde_json::to_string(&cache).unwrap();
+        let mut cache: ParsingCache = serde_json::from_str(&json).unwrap();
+        assert!(cache.cache.values().all(|parse| parse.bytecode.is_none()));
+        assert!(tokenize(&mut cache, "page.txt").bytecode.is_some());
+    }
+
+    #[test]
+    fn it_tokenizes_trim_markers_and_comments() {
+        let result = tokenize("a {%- x -%} {{- y -}} {# c #}-b").collect::<Vec<_>>();
+        let expected = vec![
+            "a ", "{%-", " x ", "-%}", " ", "{{-", " y ", "-}}", " ", "{#", " c ", "#}", "-b",
+        ];
+        assert_eq!(result, expected);
+        let result = tokenize("\\{# a \\-}} b").collect::<Vec<_>>();
+        assert_eq!(result.join(""), "{# a -}} b");
+        assert!(!result.contains(&"{#"));
+    }
 }
@@ -15,6 +15,8 @@ use axum::{
     routing::{self},
 };
 
+use asg::build_options::BuildOptions;
+use asg::build_report::BuildReport;
 use asg::process_files;
 use asg::{lua_environment::LuaEnvironment, process_file};
 use notify_debouncer_full::{
@@ -23,12 +25,54 @@ use notify_debouncer_full::{
 use tokio::sync::broadcast::{self, Sender};
 use tower_http::services::{ServeDir, ServeFile};
 
-pub async fn lib_main(input_directory: &Path, output_directory: &Path) {
-    let mut env = compile_without_server(input_directory, output_directory, None);
+/// Describes what ASG does once the first build is over.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Build the website once and return.
+    Build,
+    /// Rebuild files when they change, without starting a web server.
+    Watch,
+    /// Start a web server on `port` (or the `port` from config.lua, or 8080).
+    /// Files are rebuilt and browsers reloaded when `livereload` is set.
+    Serve {
+        port: Option<String>,
+        livereload: bool,
+    },
+    /// Legacy behaviour of `asg <input> <output>`: the `port` and `livereload`
+    /// variables from config.lua decide whether to serve and to watch.
+    FromConfig,
+}
+
+pub const DEFAULT_PORT: &str = "8080";
+
+/// Build the website, then serve and watch it according to `mode`.
+/// Returns the report of the first build.
+pub async fn lib_main(
+    input_directory: &Path,
+    output_directory: &Path,
+    mode: Mode,
+    options: &BuildOptions,
+) -> BuildReport {
+    let (mut env, report) = build(input_directory, output_directory, options);
+    report.print_summary(env.is_colored());
+
+    let (port, is_watching) = match mode {
+        Mode::Build => (None, false),
+        Mode::Watch => (None, true),
+        Mode::Serve { port, livereload } => (
+            Some(
+                port.or(env.get_config("port"))
+                    .unwrap_or(DEFAULT_PORT.to_string()),
+            ),
+            livereload,
+        ),
+        Mode::FromConfig => (env.get_config("port"), env.is_enabled("livereload")),
+    };
 
     let (debounce_event_sender, mut debounce_receiver) = broadcast::channel(16);
-    let cloned_sender = debounce_event_sender.clone();
-    let is_live_reload_enabled = env.is_enabled("livereload");
+    // Browsers are reloaded once the files of an event were processed.
+    let (reload_sender, _) = broadcast::channel(16);
+    let cloned_sender = reload_sender.clone();
     // The debouncer needs to stay alive for the whole program.
     let mut debouncer = new_debouncer(
         Duration::from_millis(100),
@@ -46,17 +90,23 @@ pub async fn lib_main(input_directory: &Path, output_directory: &Path) {
     .unwrap();
 
     #[allow(clippy::print_stdout)]
-    if is_live_reload_enabled {
+    if is_watching {
         println!("👀 Watching {}", input_directory.to_string_lossy());
         let watch_result = debouncer.watch(input_directory, RecursiveMode::Recursive);
         if let Err(e) = watch_result {
             println!("Error: Could not watch because {e}");
         }
+        // Pages depend on the layouts and std.lua stored in the assets.
+        let assets_path = env.assets_path.as_path();
+        if assets_path.exists() && !assets_path.starts_with(input_directory) {
+            let watch_result = debouncer.watch(assets_path, RecursiveMode::Recursive);
+            if let Err(e) = watch_result {
+                println!("Error: Could not watch assets because {e}");
+            }
+        }
     }
 
-    let port: Option<String> = env.get_config("port");
-
-    if let Some(port) = port {
+    let server = port.map(|port| {
         let file_404 = output_directory
             .join("404.html")
             .to_string_lossy()
@@ -86,11 +136,11 @@ pub async fn lib_main(input_directory: &Path, output_directory: &Path) {
                 std::process::exit(1);
             });
             axum::serve(listener, app).await.unwrap();
-        });
-    }
+        })
+    });
 
     // Perform the file processing on the main thread.
-    if is_live_reload_enabled {
+    if is_watching {
         loop {
             let event = debounce_receiver.recv().await;
             let Ok(event) = event else {
@@ -101,16 +151,34 @@ pub async fn lib_main(input_directory: &Path, output_directory: &Path) {
                 if env.is_enabled("debugInfo") {
                     println!("Processing {}", path.display());
                 }
-                process_file(
+                let report = process_file(
                     &mut env,
                     event.kind,
                     path,
                     input_directory,
                     output_directory,
                 );
+                if report.has_errors() {
+                    report.print_summary(env.is_colored());
+                }
+            }
+            // Ignored files, like the cache or editor swap files, do not reload the browsers.
+            let is_ignored = |path: &Path| {
+                env.ignore_rules.is_ignored(path)
+                    || env
+                        .build_cache
+                        .directory()
+                        .is_some_and(|cache_directory| path.starts_with(cache_directory))
+            };
+            if !event.paths.iter().all(|path| is_ignored(path)) {
+                let _ = reload_sender.send(event);
             }
         }
+    } else if let Some(server) = server {
+        // Without a watcher, the server is the only thing keeping the program alive.
+        let _ = server.await;
     }
+    report
 }
 
 fn websocket_route(
@@ -134,11 +202,30 @@ async fn handle_websocket(mut socket: WebSocket, debounce_event_receiver: Sender
     }
 }
 
+/// Build the website once and return the report of the build.
 pub fn compile_without_server(
     input_directory: &Path,
     output_directory: &Path,
-    asset_directory: Option<PathBuf>,
-) -> LuaEnvironment {
+    options: &BuildOptions,
+) -> BuildReport {
+    build(input_directory, output_directory, options).1
+}
+
+/// Build the website in a temporary directory to find its errors, print a summary
+/// of the build like `lib_main` and return its report.
+pub fn check(input_directory: &Path, options: &BuildOptions) -> BuildReport {
+    let output_directory = std::env::temp_dir().join(format!("asg-check-{}", std::process::id()));
+    let (env, report) = build(input_directory, &output_directory, options);
+    let _ = std::fs::remove_dir_all(&output_directory);
+    report.print_summary(env.is_colored());
+    report
+}
+
+fn build(
+    input_directory: &Path,
+    output_directory: &Path,
+    options: &BuildOptions,
+) -> (LuaEnvironment, BuildReport) {
     #[allow(clippy::print_stdout)]
     if !input_directory.exists() {
         println!(
@@ -152,7 +239,46 @@ pub fn compile_without_server(
         let _ = std::fs::create_dir_all(output_directory);
     }
 
-    let mut env = LuaEnvironment::new(input_directory, output_directory, asset_directory);
-    process_files(&mut env, input_directory, output_directory);
-    env
+    let mut env = LuaEnvironment::new(input_directory, output_directory, options);
+    let report = process_files(&mut env, input_directory, output_directory);
+    (env, report)
+}
+
+/// Remove everything inside the output directory, but keep the directory itself.
+/// Refuses to clean a directory that is or contains one of `protected_directories` (like the
+/// sources or the current directory) so that a typo does not delete a whole project.
+/// Paths are compared once `..` and symbolic links are resolved, and nothing is removed
+/// when they cannot be resolved.
+pub fn clean_output_directory(
+    output_directory: &Path,
+    protected_directories: &[PathBuf],
+) -> std::io::Result<()> {
+    if !output_directory.exists() {
+        return Ok(());
+    }
+    let output_directory = output_directory.canonicalize()?;
+    for protected_directory in protected_directories {
+        if protected_directory
+            .canonicalize()?
+            .starts_with(&output_directory)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} contains {}",
+                    output_directory.display(),
+                    protected_directory.display()
+                ),
+            ));
+        }
+    }
+    for entry in std::fs::read_dir(&output_directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
 }
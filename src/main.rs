@@ -1,45 +1,255 @@
-use std::path::{self, Path};
+use std::path::{self, Path, PathBuf};
 
-use ::asg::lib_main;
-use asg::asg::buildinfo;
+use ::asg::{Mode, check, clean_output_directory, lib_main};
+use asg::asg::{build_options::BuildOptions, build_report::BuildReport, buildinfo, scaffold};
+use clap::{Args, Parser, Subcommand};
 use colored::Colorize;
 
+/// asg - Awesome Static Generator, turns markdown into a website.
+#[derive(Parser)]
+#[command(name = "asg", disable_version_flag = true)]
+#[command(override_usage = "asg <COMMAND>\n       asg <INPUT> <OUTPUT>")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print version information.
+    #[arg(short = 'v', long)]
+    version: bool,
+
+    /// Legacy usage: `asg <input> <output>`, serving and watching according to config.lua.
+    #[arg(num_args = 2, value_names = ["INPUT", "OUTPUT"])]
+    directories: Vec<PathBuf>,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build the website once and exit.
+    Build(SiteArgs),
+    /// Build the website, serve it and rebuild it when files change.
+    Serve(ServeArgs),
+    /// Build the website and rebuild it when files change, without a web server.
+    Watch(SiteArgs),
+    /// Build the website in a temporary directory to check for errors.
+    Check(InputArgs),
+    /// Remove the content of the output directory.
+    Clean(CleanArgs),
+    /// Create a new website in the directory provided.
+    New(NewArgs),
+}
+
+/// Flags shared by all the commands that build the website.
+#[derive(Args)]
+struct BuildArgs {
+    /// Set a configuration variable, like `-D port=9000`. Takes precedence over config.lua.
+    /// `-D key` is the same as `-D key=true`.
+    #[arg(short = 'D', value_name = "KEY=VALUE", value_parser = parse_define)]
+    define: Vec<(String, String)>,
+    /// Define the `production` global, which removes the live reload script.
+    #[arg(long)]
+    production: bool,
+    /// Keep a build cache in this directory between runs, to only compile the pages that changed.
+    /// Without it, every page is compiled.
+    #[arg(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+    /// Compile every page, without reading or writing the build cache. This is the default.
+    #[arg(long, conflicts_with = "cache_dir", hide = true)]
+    no_cache: bool,
+    /// Number of pages compiled in parallel. 0 uses every available core.
+    #[arg(short, long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+}
+
+impl BuildArgs {
+    fn to_options(&self) -> BuildOptions {
+        let cache_directory = self.cache_dir.as_deref().map(resolve_path);
+        let jobs = if self.jobs == 0 {
+            std::thread::available_parallelism().map_or(1, usize::from)
+        } else {
+            self.jobs
+        };
+        BuildOptions {
+            config_overrides: self.define.clone(),
+            production: self.production,
+            cache_directory,
+            jobs,
+            ..Default::default()
+        }
+    }
+}
+
+fn parse_define(arg: &str) -> Result<(String, String), String> {
+    let (key, value) = arg.split_once('=').unwrap_or((arg, "true"));
+    if key.is_empty() {
+        return Err(format!("expected KEY=VALUE, got `{arg}`"));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[derive(Args)]
+struct InputArgs {
+    /// Directory containing config.lua and the pages of the website.
+    input: PathBuf,
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(Args)]
+struct SiteArgs {
+    /// Directory containing config.lua and the pages of the website.
+    input: PathBuf,
+    /// Directory where the website is generated.
+    output: PathBuf,
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+impl SiteArgs {
+    async fn run(&self, mode: Mode) -> BuildReport {
+        lib_main(
+            &resolve_path(&self.input),
+            &resolve_path(&self.output),
+            mode,
+            &self.build.to_options(),
+        )
+        .await
+    }
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    #[command(flatten)]
+    site: SiteArgs,
+    /// Port of the web server. Defaults to the `port` variable of config.lua, or 8080.
+    #[arg(short, long)]
+    port: Option<String>,
+    /// Do not rebuild the website and reload the browser when files change.
+    #[arg(long)]
+    no_livereload: bool,
+}
+
+#[derive(Args)]
+struct CleanArgs {
+    /// Directory containing config.lua and the pages of the website, which is never removed.
+    input: PathBuf,
+    /// Directory where the website is generated.
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct NewArgs {
+    /// Directory where the new website is created.
+    directory: PathBuf,
+}
+
+#[allow(clippy::print_stdout)]
+fn print_version() {
+    println!("asg - {}", "Awesome Static Generator".bold());
+    println!("Available at {}", "https://github.com/vanyle/asg".green());
+
+    println!("Version: {}", buildinfo::get_asg_version().blue());
+    println!();
+    println!("Build hash: {}", buildinfo::built_info::COMMIT_HASH.blue());
+    println!("Built on {}", buildinfo::built_info::BUILD_TIMESTAMP.blue());
+}
+
+fn resolve_path(arg: &Path) -> PathBuf {
+    #[allow(clippy::print_stdout)]
+    match path::absolute(arg) {
+        Ok(path) => path,
+        Err(e) => {
+            println!("{} Could not resolve path: {}", "Error:".red(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exit with a non-zero status when the build failed, so that CI catches broken pages.
+fn exit_on_errors(report: &BuildReport) {
+    if report.has_errors() {
+        std::process::exit(1);
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let cli = Cli::parse();
 
-    #[allow(clippy::print_stdout)]
-    if args.len() == 2 && (args[1] == "--version" || args[1] == "-v" || args[1] == "-version") {
-        println!("asg - {}", "Awesome Static Generator".bold());
-        println!("Available at {}", "https://github.com/vanyle/asg".green());
-
-        println!("Version: {}", buildinfo::get_asg_version().blue());
-        println!();
-        println!("Build hash: {}", buildinfo::built_info::COMMIT_HASH.blue());
-        println!("Built on {}", buildinfo::built_info::BUILD_TIMESTAMP.blue());
+    if cli.version {
+        print_version();
         std::process::exit(0);
     }
 
     #[allow(clippy::print_stdout)]
-    if args.len() != 3 {
-        println!("Usage: asg <input_directory> <output_directory>");
-        println!("Read the README.md for more information.");
-        std::process::exit(1);
-    }
+    let Some(command) = cli.command else {
+        let [input, output] = cli.directories.as_slice() else {
+            println!("Usage: asg <COMMAND>, run `asg --help` for the list of commands.");
+            println!("Read the README.md for more information.");
+            std::process::exit(1);
+        };
+        let report = lib_main(
+            &resolve_path(input),
+            &resolve_path(output),
+            Mode::FromConfig,
+            &cli.build.to_options(),
+        )
+        .await;
+        exit_on_errors(&report);
+        return;
+    };
 
-    let resolve_path = |arg: &str| -> path::PathBuf {
+    match command {
+        Command::Build(site) => {
+            let report = site.run(Mode::Build).await;
+            exit_on_errors(&report);
+        }
+        Command::Serve(args) => {
+            let mode = Mode::Serve {
+                port: args.port,
+                livereload: !args.no_livereload,
+            };
+            args.site.run(mode).await;
+        }
+        Command::Watch(site) => {
+            site.run(Mode::Watch).await;
+        }
+        Command::Check(args) => {
+            // The output is thrown away, so the cache of the real output is left alone.
+            let options = BuildOptions {
+                cache_directory: None,
+                ..args.build.to_options()
+            };
+            let report = check(&resolve_path(&args.input), &options);
+            exit_on_errors(&report);
+        }
         #[allow(clippy::print_stdout)]
-        match path::absolute(Path::new(arg)) {
-            Ok(path) => path,
-            Err(e) => {
-                println!("{} Could not resolve path: {}", "Error:".red(), e);
+        Command::Clean(args) => {
+            let input_directory = resolve_path(&args.input);
+            let output_directory = resolve_path(&args.output);
+            let mut protected_directories = vec![input_directory];
+            protected_directories.extend(std::env::current_dir().ok());
+            if let Err(e) = clean_output_directory(&output_directory, &protected_directories) {
+                println!("{} Could not clean output: {}", "Error:".red(), e);
                 std::process::exit(1);
             }
+            println!("🧹 Cleaned {}", output_directory.display());
         }
-    };
-
-    let input_directory = resolve_path(&args[1]);
-    let output_directory = resolve_path(&args[2]);
-
-    lib_main(&input_directory, &output_directory).await;
+        #[allow(clippy::print_stdout)]
+        Command::New(args) => {
+            let directory = resolve_path(&args.directory);
+            if let Err(e) = scaffold::create_site(&directory) {
+                println!("{} Could not create website: {}", "Error:".red(), e);
+                std::process::exit(1);
+            }
+            println!("✨ Created a new website in {}", directory.display());
+            println!(
+                "Run `asg serve {} <output_directory>` to see it.",
+                args.directory.display()
+            );
+        }
+    }
 }
@@ -1,30 +1,1225 @@
 /// Tests that ASG is able to compile blogs properly.
 ///
-use std::path::{self, Path};
+use std::path::{self, Path, PathBuf};
+
+use asg::asg::build_options::BuildOptions;
+use asg::asg::build_report::BuildReport;
+
+fn options() -> BuildOptions {
+    BuildOptions {
+        asset_directory: Some(path::absolute(Path::new("assets")).unwrap()),
+        ..Default::default()
+    }
+}
+
+/// Create a site in the temporary directory whose input directory contains `files`.
+/// Returns its input and output directories.
+fn site(name: &str, files: &[(&str, &str)]) -> (PathBuf, PathBuf) {
+    let site_directory = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&site_directory);
+    let input_directory = site_directory.join("src");
+    std::fs::create_dir_all(&input_directory).unwrap();
+    for (file, content) in files {
+        write(&input_directory.join(file), content);
+    }
+    (input_directory, site_directory.join("build"))
+}
+
+/// Write `content` to `file`, creating its directory.
+fn write(file: &Path, content: &str) {
+    std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+    std::fs::write(file, content).unwrap();
+}
+
+fn read(output_directory: &Path, name: &str) -> String {
+    std::fs::read_to_string(output_directory.join(name)).unwrap()
+}
+
+fn build(input_directory: &Path, output_directory: &Path) -> BuildReport {
+    asg::compile_without_server(input_directory, output_directory, &options())
+}
 
 #[tokio::test]
 async fn it_compiles_blogs() {
     let input_directory = path::absolute(Path::new("tests/blog_light_theme/src")).unwrap();
     let output_directory = path::absolute(Path::new("tests/blog_light_theme/build")).unwrap();
-    let asset_directory = path::absolute(Path::new("assets")).unwrap();
 
-    asg::compile_without_server(&input_directory, &output_directory, Some(asset_directory));
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
 }
 
 #[tokio::test]
 async fn it_generates_documentation() {
     let input_directory = path::absolute(Path::new("tests/documentation/src")).unwrap();
     let output_directory = path::absolute(Path::new("tests/documentation/build")).unwrap();
-    let asset_directory = path::absolute(Path::new("assets")).unwrap();
 
-    asg::compile_without_server(&input_directory, &output_directory, Some(asset_directory));
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
 }
 
 #[tokio::test]
 async fn it_renders_graphics() {
     let input_directory = path::absolute(Path::new("tests/graphics/src")).unwrap();
     let output_directory = path::absolute(Path::new("tests/graphics/build")).unwrap();
-    let asset_directory = path::absolute(Path::new("assets")).unwrap();
 
-    asg::compile_without_server(&input_directory, &output_directory, Some(asset_directory));
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+}
+
+#[tokio::test]
+async fn it_compiles_new_sites() {
+    let (input_directory, output_directory) = site("asg_test_new_site", &[]);
+
+    asg::asg::scaffold::create_site(&input_directory).unwrap();
+    assert!(asg::asg::scaffold::create_site(&input_directory).is_err());
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert!(
+        report
+            .pages_written
+            .contains(&output_directory.join("./index.html"))
+    );
+    assert!(output_directory.join("index.html").exists());
+    assert!(output_directory.join("posts/first_post.html").exists());
+
+    // Neither the sources nor a directory containing them are cleaned.
+    let protected_directories = [input_directory.clone()];
+    assert!(asg::clean_output_directory(&input_directory, &protected_directories).is_err());
+    let parent_directory = input_directory.parent().unwrap();
+    assert!(asg::clean_output_directory(parent_directory, &protected_directories).is_err());
+
+    asg::clean_output_directory(&output_directory, &protected_directories).unwrap();
+    assert!(!output_directory.join("index.html").exists());
+}
+
+#[tokio::test]
+async fn it_refuses_to_clean_the_input_directory() {
+    let (input_directory, output_directory) = site("asg_test_clean", &[("index.html", "a")]);
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let protected_directories = [input_directory.clone()];
+
+    // `src/..` contains the input once `..` is resolved.
+    let dot_dot_directory = input_directory.join("..");
+    assert!(asg::clean_output_directory(&dot_dot_directory, &protected_directories).is_err());
+    assert!(input_directory.join("index.html").exists());
+
+    #[cfg(unix)]
+    {
+        let link = input_directory.with_file_name("link");
+        std::os::unix::fs::symlink(input_directory.parent().unwrap(), &link).unwrap();
+        assert!(asg::clean_output_directory(&link, &protected_directories).is_err());
+        assert!(input_directory.join("index.html").exists());
+    }
+
+    // Nothing is removed when a protected directory cannot be resolved.
+    let missing_directories = [input_directory.join("missing")];
+    assert!(asg::clean_output_directory(&output_directory, &missing_directories).is_err());
+    assert!(output_directory.join("index.html").exists());
+}
+
+#[tokio::test]
+async fn it_reports_errors() {
+    let (input_directory, output_directory) = site(
+        "asg_test_errors",
+        &[
+            ("config.lua", ""),
+            ("ok.md", "# Fine"),
+            ("broken.md", "{{ nil + 1 }}"),
+            (
+                "missing_layout.md",
+                "{% setvar(\"layout\", \"does_not_exist.html\") %}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(report.has_errors());
+    assert!(report.errors.iter().any(|e| e.file.ends_with("broken.md")));
+    assert!(
+        report
+            .errors
+            .iter()
+            .any(|e| e.message.contains("does_not_exist.html"))
+    );
+    assert!(
+        report
+            .pages_written
+            .contains(&output_directory.join("./ok.html"))
+    );
+}
+
+#[tokio::test]
+async fn it_overrides_config_from_the_command_line() {
+    let (input_directory, output_directory) = site(
+        "asg_test_overrides",
+        &[
+            ("config.lua", "setvar(\"layout\", \"does_not_exist.html\")"),
+            ("index.html", "production={{ tostring(production) }}"),
+        ],
+    );
+
+    let options = BuildOptions {
+        config_overrides: vec![("layout".to_string(), String::new())],
+        production: true,
+        ..options()
+    };
+    let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(read(&output_directory, "index.html"), "production=true");
+}
+
+#[tokio::test]
+async fn it_isolates_pages_from_each_other() {
+    let (input_directory, output_directory) = site(
+        "asg_test_isolation",
+        &[
+            ("config.lua", "shared = \"config\""),
+            (
+                "c.html",
+                "{{ tostring(leak_a) }} {{ tostring(leak_b) }} {{ shared }} {{ type(site) }}",
+            ),
+        ],
+    );
+    for (page, other_page) in [("a", "b"), ("b", "a")] {
+        write(
+            &input_directory.join(format!("{page}.html")),
+            &format!(
+                "{{% setvar(\"layout\", \"missing_{page}.html\") %}}\
+                 {{% leak_{page} = 1 %}}{{{{ tostring(leak_{other_page}) }}}}"
+            ),
+        );
+    }
+
+    let report = build(&input_directory, &output_directory);
+    // a and b use a layout that does not exist, c must not inherit it.
+    assert_eq!(report.errors.len(), 4, "{:?}", report.errors);
+    assert!(report.errors.iter().all(|e| !e.file.ends_with("c.html")));
+    assert_eq!(read(&output_directory, "c.html"), "nil nil config table");
+}
+
+#[tokio::test]
+async fn it_isolates_globals_written_by_config_functions() {
+    let mut files = vec![(
+        "config.lua",
+        "count = 0\n\
+         function increment() count = count + 1 return count end\n\
+         function remember(value) last = value end",
+    )];
+    let pages = ["a.html", "b.html", "c.html", "d.html"];
+    for page in pages {
+        files.push((
+            page,
+            "{{ increment() }} {{ tostring(last) }}{% remember(file.filename) %} {{ count }}",
+        ));
+    }
+    let (input_directory, _) = site("asg_test_config_globals", &files);
+
+    for jobs in [1, 2] {
+        let output_directory = input_directory.with_file_name(format!("build{jobs}"));
+        let options = BuildOptions { jobs, ..options() };
+        let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+        assert!(!report.has_errors(), "{:?}", report.errors);
+        for page in pages {
+            assert_eq!(read(&output_directory, page), "1 nil 1");
+        }
+    }
+}
+
+#[tokio::test]
+async fn it_runs_config_once() {
+    let (input_directory, output_directory) = site(
+        "asg_test_config_once",
+        &[
+            (
+                "config.lua",
+                "site.config_runs = (site.config_runs or 0) + 1",
+            ),
+            ("a.html", "{{ site.config_runs }}"),
+            ("b.html", "{{ site.config_runs }}"),
+            ("c.html", "{{ site.config_runs }}"),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    for page in ["a", "b", "c"] {
+        assert_eq!(read(&output_directory, &format!("{page}.html")), "1");
+    }
+}
+
+#[tokio::test]
+async fn it_rebuilds_pages_depending_on_a_changed_file() {
+    use asg::asg::{lua_environment::LuaEnvironment, process_file, process_files};
+    use notify_debouncer_full::notify::{EventKind, event::ModifyKind};
+
+    let (input_directory, output_directory) = site(
+        "asg_test_dependencies",
+        &[
+            ("config.lua", ""),
+            ("data/layout.html", "v1 {{ body }}"),
+            ("data/value.txt", "10"),
+            (
+                "with_layout.html",
+                "{% setvar(\"layout\", \"data/layout.html\") %}page",
+            ),
+            ("with_data.html", "{{ read_data(\"value.txt\") }}"),
+            (
+                "list.html",
+                "{% for post in posts() do %}{{ post.word_count }}{% end %}",
+            ),
+            ("posts/post.md", "one"),
+        ],
+    );
+    let layout = input_directory.join("data/layout.html");
+    let data = input_directory.join("data/value.txt");
+    let post = input_directory.join("posts/post.md");
+
+    let options = options();
+    let mut env = LuaEnvironment::new(&input_directory, &output_directory, &options);
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+
+    let read = |name: &str| read(&output_directory, name);
+    let modify = EventKind::Modify(ModifyKind::Any);
+    assert_eq!(read("with_layout.html"), "v1 page");
+    assert_eq!(read("list.html"), "1");
+
+    write(&layout, "v2 {{ body }}");
+    let report = process_file(
+        &mut env,
+        modify,
+        &layout,
+        &input_directory,
+        &output_directory,
+    );
+    assert_eq!(report.pages_written.len(), 1);
+    assert_eq!(read("with_layout.html"), "v2 page");
+
+    write(&data, "20");
+    process_file(&mut env, modify, &data, &input_directory, &output_directory);
+    assert_eq!(read("with_data.html"), "20");
+
+    write(&post, "one two");
+    let report = process_file(&mut env, modify, &post, &input_directory, &output_directory);
+    assert_eq!(report.pages_written.len(), 2);
+    assert_eq!(read("list.html"), "2");
+}
+
+#[tokio::test]
+async fn it_reuses_the_cache_of_the_previous_build() {
+    let (input_directory, output_directory) = site(
+        "asg_test_build_cache",
+        &[
+            ("config.lua", ""),
+            ("data/layout.html", "<main>{{ body }}</main>"),
+            ("a.html", "{% setvar(\"layout\", \"data/layout.html\") %}a"),
+            ("b.html", "b"),
+            ("time.html", "{{ os.date(\"%Y\") }}"),
+            ("site.html", "{% site.visits = (site.visits or 0) + 1 %}"),
+            ("git.html", "{{ file.last_modified }}"),
+        ],
+    );
+
+    let options = BuildOptions {
+        cache_directory: Some(input_directory.with_file_name("cache")),
+        ..options()
+    };
+    let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(report.pages_written.len(), 5);
+
+    // Pages reading the time, the site table or git are compiled by every build.
+    let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+    assert_eq!(
+        report.pages_written,
+        ["git.html", "site.html", "time.html"]
+            .map(|page| output_directory.join(format!("./{page}")))
+    );
+    assert!(report.files_copied.is_empty());
+
+    write(
+        &input_directory.join("data/layout.html"),
+        "<div>{{ body }}</div>",
+    );
+    let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+    assert!(
+        report
+            .pages_written
+            .contains(&output_directory.join("./a.html"))
+    );
+    assert!(
+        !report
+            .pages_written
+            .contains(&output_directory.join("./b.html"))
+    );
+    assert_eq!(read(&output_directory, "a.html"), "<div>a</div>");
+}
+
+#[tokio::test]
+async fn it_compiles_pages_in_parallel_like_a_serial_build() {
+    let (input_directory, _) = site(
+        "asg_test_parallel",
+        &[
+            ("config.lua", ""),
+            (
+                "index.html",
+                "{% for post in posts() do %}{{ post.title }},{% end %}",
+            ),
+        ],
+    );
+    for i in 0..8 {
+        write(
+            &input_directory.join(format!("posts/post{i}.md")),
+            &format!("# Post {i}\n\nPost number {i}"),
+        );
+    }
+
+    let build = |jobs: usize| {
+        let output_directory = input_directory.with_file_name(format!("build{jobs}"));
+        let options = BuildOptions { jobs, ..options() };
+        let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+        assert!(!report.has_errors(), "{:?}", report.errors);
+        let mut outputs = vec![];
+        for entry in walkdir::WalkDir::new(&output_directory).sort_by_file_name() {
+            let entry = entry.unwrap();
+            if entry.file_type().is_file() {
+                let relative_path = entry.path().strip_prefix(&output_directory).unwrap();
+                let content = std::fs::read_to_string(entry.path()).unwrap();
+                outputs.push((relative_path.to_path_buf(), content));
+            }
+        }
+        outputs
+    };
+
+    let serial = build(1);
+    assert_eq!(serial.len(), 10);
+    assert_eq!(build(4), serial);
+}
+
+#[tokio::test]
+async fn it_skips_ignored_files() {
+    let mut files = vec![
+        ("config.lua", "setvar(\"ignore\", \"drafts/\")"),
+        (".asgignore", "*.psd\n"),
+    ];
+    for file in [
+        ".git/HEAD",
+        ".DS_Store",
+        "index.html.swp",
+        "node_modules/lib/index.js",
+        "drafts/idea.md",
+        "data/table.csv",
+        "logo.psd",
+        "index.html",
+        "posts/data/image.png",
+    ] {
+        files.push((file, "content"));
+    }
+    let (input_directory, output_directory) = site("asg_test_ignore", &files);
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+
+    let mut outputs: Vec<PathBuf> = walkdir::WalkDir::new(&output_directory)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let relative_path = entry.path().strip_prefix(&output_directory).unwrap();
+            relative_path.to_path_buf()
+        })
+        .collect();
+    outputs.sort();
+    assert_eq!(
+        outputs,
+        vec![
+            PathBuf::from("config.lua"),
+            PathBuf::from("index.html"),
+            PathBuf::from("posts/data/image.png"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn it_hides_drafts_and_scheduled_posts() {
+    let (input_directory, _) = site(
+        "asg_test_drafts",
+        &[
+            ("config.lua", ""),
+            ("posts/published.md", "Published"),
+            ("posts/draft.md", "{% draft = true %}Draft"),
+            (
+                "posts/scheduled.md",
+                "{% setvar(\"publish_date\", \"2999-01-01\") %}Scheduled",
+            ),
+            (
+                "posts/past.md",
+                "{% publish_date = \"2001-01-01 10:00\" %}Past",
+            ),
+            (
+                "index.html",
+                "{% for post in posts() do %}{{ post.title }},{% end %}",
+            ),
+        ],
+    );
+
+    let output_directory = input_directory.with_file_name("preview");
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(read("index.html"), "Past,Published,");
+    assert!(read("posts/draft.html").contains(">Draft</div>"));
+    assert!(read("posts/scheduled.html").contains(">Scheduled for 01/01/2999 00:00:00</div>"));
+    assert!(!read("posts/published.html").contains("asg-banner"));
+
+    let output_directory = input_directory.with_file_name("production");
+    let options = BuildOptions {
+        production: true,
+        ..options()
+    };
+    let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert!(output_directory.join("posts/published.html").exists());
+    assert!(output_directory.join("posts/past.html").exists());
+    assert!(!output_directory.join("posts/draft.html").exists());
+    assert!(!output_directory.join("posts/scheduled.html").exists());
+}
+
+#[tokio::test]
+async fn it_reads_front_matter() {
+    let (input_directory, output_directory) = site(
+        "asg_test_front_matter",
+        &[
+            ("config.lua", ""),
+            (
+                "data/layout.html",
+                "---\nlayout: data/outer.html\n---\n<h1>{{ page.title }}</h1>{{ body }}",
+            ),
+            ("data/outer.html", "<main>{{ body }}</main>"),
+            (
+                "posts/yaml.md",
+                "---\ntitle: From YAML\ntags: [rust, lua]\nlayout: data/layout.html\nauthor: Sam\n---\nHello",
+            ),
+            (
+                "posts/toml.md",
+                "+++\ntitle = \"From TOML\"\npublish_date = 2999-01-01\n+++\nScheduled",
+            ),
+            (
+                "posts/dated.md",
+                "---\ntitle: Dated\ndate: 2999-01-01\n---\nNot scheduled",
+            ),
+            ("rules.md", "---\nBetween rules\n\n---\nText"),
+            (
+                "index.html",
+                "{% for post in posts() do %}{{ post.title }}:{{ post.tags[2] }}:{{ post.author }}{% end %}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(
+        read("posts/yaml.html"),
+        "<main><h1>From YAML</h1><p>Hello</p></main>"
+    );
+    assert!(read("posts/toml.html").contains(">Scheduled for 01/01/2999 00:00:00</div>"));
+    assert_eq!(
+        read("rules.html"),
+        "<hr />\n<p>Between rules</p>\n<hr />\n<p>Text</p>"
+    );
+    // A `date` in the future sorts the post, but does not schedule it.
+    assert_eq!(read("index.html"), "From YAML:lua:SamDated:nil:nil");
+}
+
+#[tokio::test]
+async fn it_renders_pages_once_with_the_metadata_of_every_page() {
+    let (input_directory, output_directory) = site(
+        "asg_test_metadata",
+        &[
+            ("config.lua", "compiled = { archive = 0, index = 0 }"),
+            // The archive is walked before the posts it lists.
+            (
+                "posts/archive.html",
+                "{% setvar(\"title\", \"Archive\") compiled.archive += 1 %}\
+                 {% for post in posts() do %}{{ post.title }},{% end %}{{ compiled.archive }}",
+            ),
+            (
+                "index.html",
+                "{% compiled.index += 1 %}{{ include(\"partials/titles.html\") }}{{ compiled.index }}",
+            ),
+            (
+                "partials/titles.html",
+                "{% for post in posts() do %}{{ post.title }},{% end %}",
+            ),
+            (
+                "posts/alpha.md",
+                "---\ntitle: Alpha\ndate: 2002-01-01\n---\nAlpha",
+            ),
+            (
+                "posts/zeta.md",
+                "---\ntitle: Zeta\ndate: 2001-01-01\n---\nZeta",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(
+        report.pages_written,
+        vec![
+            output_directory.join("./index.html"),
+            output_directory.join("./posts/alpha.html"),
+            output_directory.join("./posts/archive.html"),
+            output_directory.join("./posts/zeta.html"),
+        ]
+    );
+    // Pages listing posts wait for the metadata of every page instead of being compiled again.
+    assert_eq!(
+        read(&output_directory, "posts/archive.html"),
+        "Zeta,Alpha,Archive,1"
+    );
+    assert_eq!(
+        read(&output_directory, "index.html"),
+        "Zeta,Alpha,Archive,1"
+    );
+}
+
+#[tokio::test]
+async fn it_lists_collections_declared_in_the_config() {
+    let (input_directory, output_directory) = site(
+        "asg_test_collections",
+        &[
+            (
+                "config.lua",
+                "collection(\"projects\", { sort = \"stars\", reverse = true, \
+                 layout = \"data/project.html\", permalink = \"work/:slug/\" })",
+            ),
+            ("data/project.html", "<article>{{ body }}</article>"),
+            ("projects/asg.md", "---\ntitle: ASG\nstars: 30\n---\nASG"),
+            ("projects/lua.md", "---\ntitle: Lua\nstars: 10\n---\nLua"),
+            ("projects/web.md", "---\ntitle: Web\nstars: 20\n---\nWeb"),
+            (
+                "index.html",
+                "{% for p in pages(\"projects\") do %}{{ p.title }}={{ p.url }},{% end %}\n\
+                 {% for p in pages(\"projects\", { sort = \"title\", limit = 2, \
+                 filter = function(p) return p.stars > 10 end }) do %}{{ p.title }},{% end %}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(
+        read("index.html"),
+        "ASG=/work/asg/,Web=/work/web/,Lua=/work/lua/,\nASG,Web,"
+    );
+    assert_eq!(read("work/asg/index.html"), "<article><p>ASG</p></article>");
+    assert!(!output_directory.join("projects/asg.html").exists());
+}
+
+#[tokio::test]
+async fn it_paginates_lists() {
+    let (input_directory, output_directory) = site(
+        "asg_test_pagination",
+        &[
+            ("config.lua", ""),
+            (
+                "blog/index.html",
+                "{% paginator = paginate(\"posts\", 2) %}\
+                 {{ paginator.current }}/{{ paginator.total }}:\
+                 {% for _, post in paginator.items do %}{{ post.title }},{% end %}\
+                 {{ paginator.prev_url }} {{ paginator.next_url }}",
+            ),
+        ],
+    );
+    for i in 1..=5 {
+        write(
+            &input_directory.join(format!("posts/post{i}.md")),
+            &format!("---\ntitle: Post {i}\ndate: 2020-01-0{i}\n---\nPost {i}"),
+        );
+    }
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(
+        read("blog/index.html"),
+        "1/3:Post 1,Post 2,nil /blog/page/2/"
+    );
+    assert_eq!(
+        read("blog/page/2/index.html"),
+        "2/3:Post 3,Post 4,/blog/ /blog/page/3/"
+    );
+    assert_eq!(
+        read("blog/page/3/index.html"),
+        "3/3:Post 5,/blog/page/2/ nil"
+    );
+
+    std::fs::remove_file(input_directory.join("posts/post5.md")).unwrap();
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert!(output_directory.join("blog/page/2/index.html").exists());
+    assert!(!output_directory.join("blog/page/3/index.html").exists());
+
+    // Item links start from the site root, so they work from /blog/page/2/ too.
+    write(
+        &input_directory.join("blog/index.html"),
+        "{% for _, post in paginate(\"posts\", 2).items do %}{{ post.url }} {% end %}",
+    );
+    build(&input_directory, &output_directory);
+    assert_eq!(
+        read("blog/page/2/index.html"),
+        "/posts/post3.html /posts/post4.html "
+    );
+    let link = read("blog/page/2/index.html");
+    let link = link.split(' ').next().unwrap();
+    assert!(output_directory.join(&link[1..]).exists());
+}
+
+#[tokio::test]
+async fn it_writes_emitted_pages() {
+    use asg::asg::{lua_environment::LuaEnvironment, process_files};
+
+    let (input_directory, output_directory) = site(
+        "asg_test_emit_page",
+        &[
+            ("config.lua", ""),
+            ("posts/a.md", "---\ntitle: A\ntags: [rust, lua]\n---\nA"),
+            ("posts/b.md", "---\ntitle: B\ntags: [rust]\n---\nB"),
+            (
+                "data/tag.html",
+                "{{ tag }}:{% for post in posts({ tag = tag, sort = \"title\" }) do %}{{ post.title }},{% end %}",
+            ),
+            (
+                "tags.lua",
+                "local tags = {}\n\
+                 for post in posts() do\n\
+                   for _, tag in post.tags do tags[tag] = true end\n\
+                 end\n\
+                 for tag in tags do\n\
+                   emit_page(\"tags/\" .. tag .. \"/\", { template = \"data/tag.html\" }, { tag = tag })\n\
+                 end",
+            ),
+            (
+                "index.html",
+                "{% emit_page(\"/old.html\", \"<meta http-equiv=refresh content='0; url=/'>\") %}\
+                 {% emit_page(\"/notes.txt\", \"data/tag.html\") %}Home",
+            ),
+        ],
+    );
+
+    let options = options();
+    let mut env = LuaEnvironment::new(&input_directory, &output_directory, &options);
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(read("tags/rust/index.html"), "rust:A,B,");
+    assert_eq!(read("tags/lua/index.html"), "lua:A,");
+    assert_eq!(
+        read("old.html"),
+        "<meta http-equiv=refresh content='0; url=/'>"
+    );
+    // Text naming a template is still text.
+    assert_eq!(read("notes.txt"), "data/tag.html");
+    assert!(!output_directory.join("tags.lua").exists());
+
+    write(
+        &input_directory.join("posts/a.md"),
+        "---\ntitle: A\ntags: [rust]\n---\nA",
+    );
+    // The pages a page emitted during the previous build are known, even without a cache.
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(read("tags/rust/index.html"), "rust:A,B,");
+    assert!(!output_directory.join("tags/lua").exists());
+}
+
+#[tokio::test]
+async fn it_generates_pages_from_data_files() {
+    use asg::asg::{lua_environment::LuaEnvironment, process_file, process_files};
+    use notify_debouncer_full::notify::{EventKind, event::ModifyKind};
+
+    let (input_directory, output_directory) = site(
+        "asg_test_data_pages",
+        &[
+            (
+                "config.lua",
+                "data_pages(\"speakers.csv\", { template = \"data/speaker.html\", url = \"/speakers/{slug}.html\" })\n\
+                 data_pages(\"products.json\", { template = \"data/product.html\", url = \"/products/{id}/\" })",
+            ),
+            (
+                "data/speakers.csv",
+                "slug,name,bio\nada,\"Lovelace, Ada\",\ngrace,Grace Hopper,COBOL\n",
+            ),
+            ("data/speaker.html", "{{ row.name }}:{{ row.bio }}"),
+            (
+                "data/products.json",
+                r#"[{ "id": 1, "name": "Lamp", "price": 20 }]"#,
+            ),
+            ("data/product.html", "{{ row.name }} costs {{ row.price }}"),
+        ],
+    );
+    let speakers = input_directory.join("data/speakers.csv");
+
+    let options = options();
+    let mut env = LuaEnvironment::new(&input_directory, &output_directory, &options);
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(read("speakers/ada.html"), "Lovelace, Ada:");
+    assert_eq!(read("speakers/grace.html"), "Grace Hopper:COBOL");
+    assert_eq!(read("products/1/index.html"), "Lamp costs 20");
+    assert!(!output_directory.join("data").exists());
+
+    // Watching the data file writes its pages again.
+    write(&speakers, "slug,name,bio\nada,Ada Lovelace,Notes\n");
+    let modify = EventKind::Modify(ModifyKind::Any);
+    let report = process_file(
+        &mut env,
+        modify,
+        &speakers,
+        &input_directory,
+        &output_directory,
+    );
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(read("speakers/ada.html"), "Ada Lovelace:Notes");
+    assert!(!output_directory.join("speakers/grace.html").exists());
+
+    write(&speakers, "slug,name,bio\nada,Ada,Notes\n");
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(read("speakers/ada.html"), "Ada:Notes");
+
+    write(&speakers, "slug,name\n,Nobody\n");
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(report.errors.iter().any(|e| e.message.contains("slug")));
+}
+
+#[tokio::test]
+async fn it_writes_taxonomy_pages() {
+    use asg::asg::{lua_environment::LuaEnvironment, process_file, process_files};
+    use notify_debouncer_full::notify::{EventKind, event::ModifyKind};
+
+    let (input_directory, output_directory) = site(
+        "asg_test_taxonomies",
+        &[
+            (
+                "config.lua",
+                "taxonomy_pages(\"tags\", { template = \"data/tag.html\", index_template = \"data/tags.html\" })\n\
+                 taxonomy_pages(\"categories\", { template = \"data/tag.html\", url = \"/c/{slug}.html\" })",
+            ),
+            (
+                "posts/a.md",
+                "---\ntitle: A\ntags: [Rust, Lua Tips]\ncategories: code\ndate: 2024-01-01\n---\nA",
+            ),
+            (
+                "posts/b.md",
+                "---\ntitle: B\ntags: [Rust]\ndate: 2025-01-01\n---\nB",
+            ),
+            (
+                "data/tag.html",
+                "{{ term.name }}:{% for _, post in term.pages do %}{{ post.title }},{% end %}",
+            ),
+            (
+                "data/tags.html",
+                "{% for _, term in terms do %}{{ term.name }}={{ term.count }}@{{ term.url }} {% end %}",
+            ),
+            (
+                "index.html",
+                "{% for _, term in taxonomy(\"tags\", { limit = 1 }) do %}{{ term.slug }}:{{ term.pages[1].title }} {% end %}\
+                 {{ taxonomy_url(\"tags\", \"Lua Tips\") }} {{ tostring(taxonomy_url(\"authors\")) }}",
+            ),
+            ("about.md", "No tags"),
+        ],
+    );
+
+    let options = options();
+    let mut env = LuaEnvironment::new(&input_directory, &output_directory, &options);
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(read("tags/rust/index.html"), "Rust:B,A,");
+    assert_eq!(read("tags/lua-tips/index.html"), "Lua Tips:A,");
+    assert_eq!(
+        read("tags/index.html"),
+        "Lua Tips=1@/tags/lua-tips/ Rust=2@/tags/rust/ "
+    );
+    assert_eq!(read("c/code.html"), "code:A,");
+    assert_eq!(read("index.html"), "lua-tips:A rust:B /tags/lua-tips/ nil");
+
+    // Only the pages with terms change the pages listing the terms.
+    let modify = EventKind::Modify(ModifyKind::Any);
+    let about = input_directory.join("about.md");
+    write(&about, "Still no tags");
+    let report = process_file(
+        &mut env,
+        modify,
+        &about,
+        &input_directory,
+        &output_directory,
+    );
+    assert_eq!(
+        report.pages_written,
+        vec![output_directory.join("./about.html")]
+    );
+
+    let b = input_directory.join("posts/b.md");
+    write(&b, "---\ntitle: B2\ntags: [Rust]\ndate: 2025-01-01\n---\nB");
+    let report = process_file(&mut env, modify, &b, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(read("index.html"), "lua-tips:A rust:B2 /tags/lua-tips/ nil");
+    assert_eq!(read("tags/rust/index.html"), "Rust:B2,A,");
+}
+
+#[tokio::test]
+async fn it_gives_every_term_its_own_page() {
+    let (input_directory, output_directory) = site(
+        "asg_test_taxonomy_slugs",
+        &[
+            (
+                "config.lua",
+                "taxonomy_pages(\"tags\", { template = \"data/tag.html\" })",
+            ),
+            (
+                "posts/a.md",
+                "---\ntitle: A\ntags: [C++, Rust]\ndate: 2024-01-02\n---\nA",
+            ),
+            (
+                "posts/b.md",
+                "---\ntitle: B\ntags: [C#, rust]\ndate: 2024-01-01\n---\nB",
+            ),
+            ("posts/c.md", "---\ntitle: C\ntags: [c, \"+++\"]\n---\nC"),
+            (
+                "data/tag.html",
+                "{{ term.name }}:{% for _, post in term.pages do %}{{ post.url }},{% end %}",
+            ),
+            (
+                "index.html",
+                "{% for _, term in taxonomy(\"tags\") do %}{{ term.slug }} {% end %}\
+                 {{ taxonomy_url(\"tags\", \"C++\") }}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    let read = |name: &str| read(&output_directory, name);
+    // Terms sharing a slug are numbered, and `rust` is `Rust`.
+    assert_eq!(read("tags/c/index.html"), "c:/posts/c.html,");
+    assert_eq!(read("tags/c-2/index.html"), "C#:/posts/b.html,");
+    assert_eq!(read("tags/c-3/index.html"), "C++:/posts/a.html,");
+    assert_eq!(
+        read("tags/rust/index.html"),
+        "Rust:/posts/a.html,/posts/b.html,"
+    );
+    // A term without letters or digits still has a page.
+    assert_eq!(read("tags/term/index.html"), "+++:/posts/c.html,");
+    assert_eq!(read("index.html"), "term c c-2 c-3 rust /tags/c-3/");
+}
+
+#[tokio::test]
+async fn it_runs_build_hooks() {
+    use asg::asg::{lua_environment::LuaEnvironment, process_files};
+
+    let (input_directory, output_directory) = site(
+        "asg_test_hooks",
+        &[
+            (
+                "config.lua",
+                "local copied = {}\n\
+                 function on_build_start() copied = {} end\n\
+                 function on_file_copied(source, destination)\n\
+                   if string.find(destination, \"png$\") then table.insert(copied, destination) end\n\
+                 end\n\
+                 function on_page_rendered(page, html)\n\
+                   if page.output == \"index.html\" then return string.gsub(html, \"Hello\", \"Bye\") end\n\
+                 end\n\
+                 function on_build_end(site)\n\
+                   local urls = {}\n\
+                   for _, page in site.pages do table.insert(urls, page.url) end\n\
+                   emit_page(\"/sitemap.txt\", table.concat(urls, \",\") .. \" \" .. #copied .. \" \" .. site.errors)\n\
+                 end",
+            ),
+            ("index.html", "Hello {{ 1 + 1 }}"),
+            ("about.html", "Hello"),
+            ("logo.png", "png"),
+        ],
+    );
+
+    let options = options();
+    let mut env = LuaEnvironment::new(&input_directory, &output_directory, &options);
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(read("index.html"), "Bye 2");
+    assert_eq!(read("about.html"), "Hello");
+    assert_eq!(read("sitemap.txt"), "./about.html,./index.html 1 0");
+
+    // Without the hook, the files it wrote are removed.
+    write(&input_directory.join("config.lua"), "");
+    let report = process_files(&mut env, &input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert_eq!(read("index.html"), "Hello 2");
+    assert!(!output_directory.join("sitemap.txt").exists());
+}
+
+#[tokio::test]
+async fn it_includes_partials() {
+    let (input_directory, output_directory) = site(
+        "asg_test_partials",
+        &[
+            ("config.lua", ""),
+            (
+                "partials/card.html",
+                "{% label = string.upper(title) %}<a href=\"{{ url }}\">{{ label }}</a>{{ include(\"partials/note.md\", { text = site_name }) }}",
+            ),
+            ("partials/note.md", "*{{ text }}*"),
+            (
+                "partials/loop.html",
+                "{{ include(\"partials/loop.html\") }}",
+            ),
+            (
+                "index.html",
+                "{% site_name = \"ASG\" %}{{ include(\"partials/card.html\", { title = \"a\", url = \"/a.html\" }) }}|{{ tostring(label) }}",
+            ),
+            (
+                "broken.html",
+                "{{ include(\"partials/loop.html\") }}{{ include(\"partials/missing.html\") }}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert_eq!(
+        read(&output_directory, "index.html"),
+        "<a href=\"/a.html\">A</a><p><em>ASG</em></p>|nil"
+    );
+    assert!(!output_directory.join("partials").exists());
+    assert!(
+        report
+            .errors
+            .iter()
+            .any(|e| e.message.contains("Infinite inclusion loop in partials"))
+    );
+    assert!(report.errors.iter().any(|e| {
+        e.message
+            .contains("Partial partials/missing.html not found")
+    }));
+}
+
+#[tokio::test]
+async fn it_fills_blocks_of_layouts() {
+    let (input_directory, output_directory) = site(
+        "asg_test_blocks",
+        &[
+            ("config.lua", ""),
+            (
+                "data/outer.html",
+                "<head>{% block head %}H{% endblock %}</head>\
+                 <nav>{% block sidebar %}{% for i = 1, 2 do %}{{ i }}{% end %}{% endblock %}</nav>\
+                 {{ body }}<footer>{% block footer %}F{% endblock %}</footer>",
+            ),
+            (
+                "data/inner.html",
+                "{% setvar(\"layout\", \"data/outer.html\") %}\
+                 {% block head append %}+inner{% endblock %}<main>{{ body }}</main>",
+            ),
+            (
+                "index.md",
+                "---\nlayout: data/inner.html\n---\n\
+                 {% block sidebar %}*links*{% endblock %}{% block head prepend %}<meta name=\"page\">{% endblock %}Text",
+            ),
+            (
+                "docs.md",
+                "---\nlayout: themes/paper.html\n---\n# Docs\n\n{% block sidebar %}Custom navigation{% endblock %}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(
+        read("index.html"),
+        "<head><meta name=\"page\">H+inner</head><nav><p><em>links</em></p></nav><main><p>Text</p></main><footer>F</footer>"
+    );
+    let docs = read("docs.html");
+    assert!(docs.contains("<p>Custom navigation</p>"));
+    assert!(!docs.contains("class=\"nav-1\""));
+}
+
+#[tokio::test]
+async fn it_renders_nested_statements() {
+    let (input_directory, output_directory) = site(
+        "asg_test_statements",
+        &[
+            ("config.lua", ""),
+            (
+                "index.html",
+                "{% for i = 1, 4 do %}\n{% local double = i * 2 %}\
+                 {% if i == 1 then %}one{% elseif i == 2 then %}{% for j = 1, i do %}[{{ j }}]{% end %}\
+                 {% else %}{{ double }}{% end %},{% end %}|{{ tostring(double) }}",
+            ),
+            ("unbalanced.html", "{% if true then %}a{% end %}{% end %}"),
+            (
+                "unclosed.html",
+                "{% for i = 1, 2 do %}{% if i then %}a{% end %}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert_eq!(read(&output_directory, "index.html"), "one,[1][2],6,8,|nil");
+    assert!(
+        report
+            .errors
+            .iter()
+            .any(|e| e.message.contains("{% end %} does not close anything"))
+    );
+    assert!(report.errors.iter().any(|e| {
+        e.message
+            .contains("{% for i = 1, 2 do %} is missing its {% end %}")
+    }));
+}
+
+#[tokio::test]
+async fn it_trims_whitespace_around_tags() {
+    let (input_directory, output_directory) = site(
+        "asg_test_trim_markers",
+        &[
+            ("config.lua", ""),
+            (
+                "table.txt",
+                "{# Prices of {{ fruits }} #}\n\
+                 | Fruit | Price |\n\
+                 {%- for _, fruit in ipairs({ \"apple\", \"pear\" }) do %}\n\
+                 | {{ fruit }} | {{- #fruit -}} |\n\
+                 {%- end %}\n\
+                 \\{# {{ 1 + 1 }} \\%}",
+            ),
+            ("unclosed.txt", "a {# b"),
+            (
+                "code.txt",
+                "{% local items = {{1}, {2}} %}{{ #items }} {{ ({#items})[1] }}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert_eq!(
+        read(&output_directory, "table.txt"),
+        "\n| Fruit | Price |\n| apple |5|\n| pear |4|\n{# 2 %}"
+    );
+    assert_eq!(read(&output_directory, "code.txt"), "2 2");
+    assert_eq!(read(&output_directory, "unclosed.txt"), "a {# b");
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+}
+
+#[tokio::test]
+async fn it_reports_errors_at_template_positions() {
+    let (input_directory, output_directory) = site(
+        "asg_test_source_maps",
+        &[
+            ("config.lua", ""),
+            (
+                "index.md",
+                "---\nlayout: data/layout.html\n---\n# Title\n\n\
+                 {% function broken() return nil .. \"x\" end %}\n\
+                 The {{ missing.field }} value\n",
+            ),
+            (
+                "data/layout.html",
+                "<main>{{ body }}</main>{{ broken() }}\n{%\nlocal a = 1\nlocal b = nil + a\n%}",
+            ),
+            ("unbalanced.html", "a\n  {% end %}"),
+            ("call.html", "x\n\n{{ f(1,\n2 }}"),
+            (
+                "failing.txt",
+                "before\n{% error(\"boom\") %}\nafter {{ 1 + 1 }}",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    let has_error = |location: &str| report.errors.iter().any(|e| e.message.contains(location));
+    assert!(
+        has_error("index.md:7:7: attempt to index nil"),
+        "{:?}",
+        report.errors
+    );
+    assert!(has_error("layout.html:4:1: attempt to perform arithmetic"));
+    assert!(has_error("index.md:6:3: attempt to concatenate"));
+    assert!(has_error(
+        "unbalanced.html:2:6: {% end %} does not close anything"
+    ));
+    assert!(has_error("failing.txt:2:3: boom"), "{:?}", report.errors);
+    // Lines mentioned by the message are lines of the template too.
+    assert!(has_error(
+        "call.html:4:1: Expected ')' (to close '(' at line 3)"
+    ));
+    assert_eq!(read(&output_directory, "failing.txt"), "before\n\nafter 2");
+    assert!(!output_directory.join("unbalanced.html").exists());
+
+    // A page broken after a build keeps its previous output.
+    write(&input_directory.join("unbalanced.html"), "a");
+    build(&input_directory, &output_directory);
+    write(&input_directory.join("unbalanced.html"), "b {% end %}");
+    build(&input_directory, &output_directory);
+    assert_eq!(read(&output_directory, "unbalanced.html"), "a");
+}
+
+#[tokio::test]
+async fn it_escapes_values() {
+    let (input_directory, output_directory) = site(
+        "asg_test_escaping",
+        &[
+            ("config.lua", "name = \"Tom & <Jerry>\""),
+            ("partials/name.html", "<b>{{ name }}</b>"),
+            (
+                "index.html",
+                "{{ name }}|{{ raw(name) }}|{{ include(\"partials/name.html\") }}|\
+                 {{ escape_attr(\"a b\") }}|{{ escape_url(name) }}|{{ escape_js(\"`${x}`\") }}|{{ 1 }}",
+            ),
+            ("notes.txt", "{{ name }}"),
+            (
+                "shapes.md",
+                "{{ circle(5) }}{{ plot(function(x) return x end) }}",
+            ),
+            ("data/layout.html", "<main>{{ body }}</main>"),
+            (
+                "page.html",
+                "---\nlayout: data/layout.html\n---\n<p>{{ name }}</p>",
+            ),
+        ],
+    );
+
+    let report = build(&input_directory, &output_directory);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    let read = |name: &str| read(&output_directory, name);
+    assert_eq!(
+        read("index.html"),
+        "Tom &amp; &lt;Jerry&gt;|Tom & <Jerry>|<b>Tom &amp; &lt;Jerry&gt;</b>|\
+         a&#x20;b|Tom%20%26%20%3CJerry%3E|\\u0060\\u0024{x}\\u0060|1"
+    );
+    assert_eq!(read("notes.txt"), "Tom & <Jerry>");
+    // The SVG of the helpers of std.lua is trusted.
+    let shapes = read("shapes.html");
+    assert_eq!(shapes.matches("<svg").count(), 2, "{shapes}");
+    assert_eq!(
+        read("page.html"),
+        "<main><p>Tom &amp; &lt;Jerry&gt;</p></main>"
+    );
+
+    let options = BuildOptions {
+        config_overrides: vec![("autoescape".to_string(), "false".to_string())],
+        ..options()
+    };
+    let report = asg::compile_without_server(&input_directory, &output_directory, &options);
+    assert!(!report.has_errors(), "{:?}", report.errors);
+    assert!(read("index.html").starts_with("Tom & <Jerry>|Tom & <Jerry>|<b>Tom & <Jerry></b>|"));
 }